@@ -0,0 +1,370 @@
+//! Generates tag constants, field enums, message types and field metadata
+//! from QuickFIX style XML dictionary.
+//!
+//! Dictionary is taken from FIXR_DICTIONARY environment variable (spec/FIX42.xml by default),
+//! result is included by src/fix_dict.rs
+
+extern crate xml;
+
+#[path = "src/qfxml.rs"]
+mod qfxml;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::fmt::Write as FmtWrite;
+use std::path::Path;
+use qfxml::{Spec, Member, FieldType};
+
+/// Session level message types are handled by FixMsgType itself
+const SESSION_MSG_TYPES: &'static [&'static str] = &["0", "1", "2", "3", "4", "5", "A", "j"];
+
+fn main()
+{
+    let dict = env::var("FIXR_DICTIONARY").unwrap_or(String::from("spec/FIX42.xml"));
+    println!("cargo:rerun-if-changed={}", dict);
+    println!("cargo:rerun-if-env-changed=FIXR_DICTIONARY");
+
+    let file = File::open(&dict).unwrap_or_else(|e| panic!("Can't open {}: {}", dict, e));
+    let spec = qfxml::parse(file).unwrap_or_else(|e| panic!("{}: {}", dict, e));
+    let code = generate(&spec, &dict).unwrap_or_else(|e| panic!("{}: {}", dict, e));
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("fix_dictionary.rs");
+    File::create(&out).and_then(|mut f| f.write_all(code.as_bytes())).unwrap();
+}
+
+/// "SELL_SHORT" -> "SellShort"
+fn camel(s: &str) -> String
+{
+    let mut res = String::new();
+    for word in s.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            res.extend(first.to_uppercase());
+            res.push_str(&chars.as_str().to_lowercase());
+        }
+    }
+    match res.chars().next() {
+        Some(c) if c.is_alphabetic() => res,
+        _ => format!("V{}", res),
+    }
+}
+
+/// Enum variants of the field, names made unique
+fn variants(values: &[(String, String)]) -> Vec<(String, String, String)>
+{
+    let mut res: Vec<(String, String, String)> = vec![];
+    for &(ref value, ref description) in values {
+        let mut name = camel(description);
+        if res.iter().any(|v| v.0 == name) {
+            name = format!("{}{}", name, camel(value));
+        }
+        res.push((name, value.clone(), description.clone()));
+    }
+    res
+}
+
+fn generate(spec: &Spec, source: &str) -> Result<String, String>
+{
+    let mut s = String::new();
+    let groups = spec.groups()?;
+    let w = |e: ::std::fmt::Error| e.to_string();
+
+    writeln!(s, "// Generated from {}, do not edit", source).map_err(&w)?;
+    writeln!(s, "pub const BEGIN_STRING: &'static str = {:?};\n", spec.begin_string).map_err(&w)?;
+
+    // tags
+    writeln!(s, "pub mod tags {{").map_err(&w)?;
+    for f in &spec.fields {
+        writeln!(s, "    pub const {}: u32 = {};", f.name, f.number).map_err(&w)?;
+    }
+    writeln!(s, "}}\n").map_err(&w)?;
+
+    // enums of enumerated fields
+    writeln!(s, "pub mod fields {{").map_err(&w)?;
+    for f in spec.fields.iter().filter(|f| !f.values.is_empty()) {
+        let vs = variants(&f.values);
+        writeln!(s, "    #[derive(Debug, Clone, Copy, PartialEq)]").map_err(&w)?;
+        writeln!(s, "    pub enum {} {{", f.name).map_err(&w)?;
+        for v in &vs {
+            writeln!(s, "        {},", v.0).map_err(&w)?;
+        }
+        writeln!(s, "    }}\n").map_err(&w)?;
+        writeln!(s, "    impl {} {{", f.name).map_err(&w)?;
+        writeln!(s, "        pub fn from_bytes(v: &[u8]) -> Option<{}> {{", f.name).map_err(&w)?;
+        writeln!(s, "            match v {{").map_err(&w)?;
+        for v in &vs {
+            writeln!(s, "                b{:?} => Some({}::{}),", v.1, f.name, v.0).map_err(&w)?;
+        }
+        writeln!(s, "                _ => None,\n            }}\n        }}\n").map_err(&w)?;
+        writeln!(s, "        pub fn as_bytes(&self) -> &'static [u8] {{").map_err(&w)?;
+        writeln!(s, "            match *self {{").map_err(&w)?;
+        for v in &vs {
+            writeln!(s, "                {}::{} => b{:?},", f.name, v.0, v.1).map_err(&w)?;
+        }
+        writeln!(s, "            }}\n        }}\n").map_err(&w)?;
+        writeln!(s, "        pub fn description(&self) -> &'static str {{").map_err(&w)?;
+        writeln!(s, "            match *self {{").map_err(&w)?;
+        for v in &vs {
+            writeln!(s, "                {}::{} => {:?},", f.name, v.0, v.2).map_err(&w)?;
+        }
+        writeln!(s, "            }}\n        }}\n    }}\n").map_err(&w)?;
+        // Default only to allow Default on generated messages
+        writeln!(s, "    impl Default for {} {{\n        fn default() -> {} {{\n            {}::{}\n        }}\n    }}\n",
+                 f.name, f.name, f.name, vs[0].0).map_err(&w)?;
+        writeln!(s, "    impl ::message::FieldValue for {} {{", f.name).map_err(&w)?;
+        writeln!(s, "        fn from_fix(v: &[u8]) -> Option<Self> {{\n            {}::from_bytes(v)\n        }}\n", f.name).map_err(&w)?;
+        writeln!(s, "        fn write_fix<H: ::fix::FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) {{").map_err(&w)?;
+        writeln!(s, "            h.tag_value(tag, self.as_bytes())\n        }}\n    }}\n").map_err(&w)?;
+    }
+    writeln!(s, "}}\n").map_err(&w)?;
+
+    // application message types
+    let app_msgs: Vec<_> = spec.messages.iter()
+        .filter(|m| !SESSION_MSG_TYPES.contains(&m.msg_type.as_str()))
+        .collect();
+    writeln!(s, "#[derive(Debug, Clone, Copy, PartialEq)]").map_err(&w)?;
+    writeln!(s, "pub enum MsgType {{").map_err(&w)?;
+    for m in &app_msgs {
+        writeln!(s, "    {},", m.name).map_err(&w)?;
+    }
+    writeln!(s, "}}\n").map_err(&w)?;
+    writeln!(s, "impl ::fix::FixAppMsgType for MsgType {{").map_err(&w)?;
+    writeln!(s, "    fn lookup(btype: &[u8]) -> Option<Self> {{\n        match btype {{").map_err(&w)?;
+    for m in &app_msgs {
+        writeln!(s, "            b{:?} => Some(MsgType::{}),", m.msg_type, m.name).map_err(&w)?;
+    }
+    writeln!(s, "            _ => None,\n        }}\n    }}\n").map_err(&w)?;
+    writeln!(s, "    fn as_bytes(&self) -> &[u8] {{\n        match *self {{").map_err(&w)?;
+    for m in &app_msgs {
+        writeln!(s, "            MsgType::{} => b{:?},", m.name, m.msg_type).map_err(&w)?;
+    }
+    writeln!(s, "        }}\n    }}\n}}\n").map_err(&w)?;
+
+    // field metadata
+    writeln!(s, "pub fn field_type(tag: u32) -> Option<::qfxml::FieldType> {{\n    match tag {{").map_err(&w)?;
+    for f in &spec.fields {
+        writeln!(s, "        {} => Some(::qfxml::FieldType::{}),", f.number, f.field_type.variant()).map_err(&w)?;
+    }
+    writeln!(s, "        _ => None,\n    }}\n}}\n").map_err(&w)?;
+
+    writeln!(s, "pub fn field_name(tag: u32) -> Option<&'static str> {{\n    match tag {{").map_err(&w)?;
+    for f in &spec.fields {
+        writeln!(s, "        {} => Some({:?}),", f.number, f.name).map_err(&w)?;
+    }
+    writeln!(s, "        _ => None,\n    }}\n}}\n").map_err(&w)?;
+
+    writeln!(s, "pub fn field_tag(name: &str) -> Option<u32> {{\n    match name {{").map_err(&w)?;
+    for f in &spec.fields {
+        writeln!(s, "        {:?} => Some({}),", f.name, f.number).map_err(&w)?;
+    }
+    writeln!(s, "        _ => None,\n    }}\n}}\n").map_err(&w)?;
+
+    writeln!(s, "pub fn value_name(tag: u32, value: &[u8]) -> Option<&'static str> {{\n    match tag {{").map_err(&w)?;
+    for f in spec.fields.iter().filter(|f| !f.values.is_empty()) {
+        writeln!(s, "        {} => fields::{}::from_bytes(value).map(|v| v.description()),", f.number, f.name).map_err(&w)?;
+    }
+    writeln!(s, "        _ => None,\n    }}\n}}\n").map_err(&w)?;
+
+    writeln!(s, "pub fn group_delimiter(no_tag: u32) -> Option<u32> {{\n    match no_tag {{").map_err(&w)?;
+    for g in &groups {
+        writeln!(s, "        {} => Some({}),", g.no_tag, g.delimiter).map_err(&w)?;
+    }
+    writeln!(s, "        _ => None,\n    }}\n}}\n").map_err(&w)?;
+
+    writeln!(s, "pub fn group_member(no_tag: u32, tag: u32) -> bool {{\n    match (no_tag, tag) {{").map_err(&w)?;
+    for g in &groups {
+        for m in &g.members {
+            writeln!(s, "        ({}, {}) => true,", g.no_tag, m).map_err(&w)?;
+        }
+    }
+    writeln!(s, "        _ => false,\n    }}\n}}\n").map_err(&w)?;
+
+    let mut header = vec![];
+    for m in spec.expand(&spec.header)? {
+        match m {
+            qfxml::Member::Field { ref name, .. } | qfxml::Member::Group { ref name, .. } => header.push(spec.tag(name)?),
+            _ => {},
+        }
+    }
+    writeln!(s, "pub fn is_header_field(tag: u32) -> bool {{\n    match tag {{").map_err(&w)?;
+    for t in &header {
+        writeln!(s, "        {} => true,", t).map_err(&w)?;
+    }
+    writeln!(s, "        _ => false,\n    }}\n}}\n").map_err(&w)?;
+
+    writeln!(s, "{}", DICTIONARY_IMPL).map_err(&w)?;
+
+    // typed messages
+    writeln!(s, "pub mod messages {{").map_err(&w)?;
+    writeln!(s, "    use ::message::{{FieldValue, DecodeState}};\n").map_err(&w)?;
+    for m in &spec.messages {
+        let members = spec.expand(&m.members)?;
+        gen_struct(spec, &mut s, &m.name, &members, Some(m)).map_err(&w)?;
+    }
+    writeln!(s, "}}").map_err(&w)?;
+    Ok(s)
+}
+
+/// "ClOrdID" -> "cl_ord_id"
+fn snake(s: &str) -> String
+{
+    let chars: Vec<char> = s.chars().collect();
+    let mut res = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).map_or(false, |n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_lower) {
+                res.push('_');
+            }
+        }
+        res.extend(c.to_lowercase());
+    }
+    match res.as_str() {
+        "type" | "ref" | "match" | "in" | "for" | "move" | "override" | "final" => format!("{}_", res),
+        _ => res,
+    }
+}
+
+fn rust_type(f: &qfxml::Field) -> String
+{
+    match f.field_type {
+        FieldType::Boolean => return String::from("bool"),
+        FieldType::MultipleValueString | FieldType::MultipleCharValue => {},
+        _ if !f.values.is_empty() => return format!("::fix_dict::fields::{}", f.name),
+        _ => {},
+    }
+    String::from(match f.field_type {
+        FieldType::Int => "i64",
+        FieldType::Length | FieldType::SeqNum | FieldType::NumInGroup |
+        FieldType::TagNum | FieldType::DayOfMonth => "u32",
+        FieldType::Float | FieldType::Qty | FieldType::Price | FieldType::PriceOffset |
+        FieldType::Amt | FieldType::Percentage => "f64",
+        FieldType::Char => "char",
+        FieldType::Data | FieldType::XmlData => "Vec<u8>",
+        _ => "String",
+    })
+}
+
+/// Generates struct for a message (msg is Some) or a group entry, nested group entries
+/// are named after the enclosing struct: MarketDataSnapshotFullRefreshNoMDEntries
+fn gen_struct(spec: &Spec, s: &mut String, name: &str, members: &[Member], msg: Option<&qfxml::Message>)
+    -> Result<(), ::std::fmt::Error>
+{
+    // (tag, member name, snake name, required, Some((entry struct, delimiter, members)) for groups)
+    let mut fields = vec![];
+    for m in members {
+        match *m {
+            Member::Field { ref name, required } => {
+                let f = spec.field(name).unwrap();
+                fields.push((f.number, f, snake(name), required, None));
+            },
+            Member::Group { name: ref gname, required, ref members } => {
+                let f = spec.field(gname).unwrap();
+                let delimiter = match members.first() {
+                    Some(&Member::Field { ref name, .. }) | Some(&Member::Group { ref name, .. }) => spec.field(name).unwrap().number,
+                    _ => continue,
+                };
+                fields.push((f.number, f, snake(gname), required, Some((format!("{}{}", name, gname), delimiter, members))));
+            },
+            Member::Component { .. } => {},
+        }
+    }
+
+    for &(_, _, _, _, ref group) in &fields {
+        if let Some((ref entry, _, members)) = *group {
+            gen_struct(spec, s, entry, members, None)?;
+        }
+    }
+
+    writeln!(s, "    #[derive(Debug, Clone, Default, PartialEq)]")?;
+    writeln!(s, "    pub struct {} {{", name)?;
+    for &(_, f, ref field, required, ref group) in &fields {
+        match *group {
+            Some((ref entry, _, _)) => writeln!(s, "        pub {}: Vec<{}>,", field, entry)?,
+            None if required => writeln!(s, "        pub {}: {},", field, rust_type(f))?,
+            None => writeln!(s, "        pub {}: Option<{}>,", field, rust_type(f))?,
+        }
+    }
+    writeln!(s, "        _state: DecodeState,\n    }}\n")?;
+
+    writeln!(s, "    impl {} {{", name)?;
+    writeln!(s, "        fn accept(&mut self, t: u32, v: &[u8]) -> bool {{")?;
+    writeln!(s, "            if let Some(no_tag) = self._state.group {{")?;
+    writeln!(s, "                if self.group_value(no_tag, t, v) {{\n                    return true;\n                }}")?;
+    writeln!(s, "                self._state.group = None;\n            }}")?;
+    writeln!(s, "            match t {{")?;
+    for &(tag, _, ref field, required, ref group) in &fields {
+        match *group {
+            Some(_) => writeln!(s, "                {} => {{ self.{}.clear(); self._state.group_start({}) }},", tag, field, tag)?,
+            None if required => writeln!(s, "                {} => self._state.set({}, v, &mut self.{}),", tag, tag, field)?,
+            None => writeln!(s, "                {} => self._state.set_opt({}, v, &mut self.{}),", tag, tag, field)?,
+        }
+    }
+    writeln!(s, "                _ => return false,\n            }}\n            true\n        }}\n")?;
+
+    writeln!(s, "        fn group_value(&mut self, no_tag: u32, t: u32, v: &[u8]) -> bool {{")?;
+    writeln!(s, "            match no_tag {{")?;
+    for &(tag, _, ref field, _, ref group) in &fields {
+        if let Some((_, delimiter, _)) = *group {
+            writeln!(s, "                {} => {{", tag)?;
+            writeln!(s, "                    if t == {} {{\n                        self.{}.push(Default::default());\n                    }}", delimiter, field)?;
+            writeln!(s, "                    self.{}.last_mut().map_or(false, |e| e.accept(t, v))\n                }},", field)?;
+        }
+    }
+    writeln!(s, "                _ => false,\n            }}\n        }}\n")?;
+
+    writeln!(s, "        fn encode_fields<H: ::fix::FixTagHandler + ?Sized>(&self, h: &mut H) {{")?;
+    for &(tag, _, ref field, required, ref group) in &fields {
+        match *group {
+            Some(_) => {
+                writeln!(s, "            if !self.{}.is_empty() {{", field)?;
+                writeln!(s, "                h.tag_value({}, self.{}.len().to_string().as_bytes());", tag, field)?;
+                writeln!(s, "                for e in &self.{} {{\n                    e.encode_fields(h);\n                }}\n            }}", field)?;
+            },
+            None if required => writeln!(s, "            self.{}.write_fix({}, h);", field, tag)?,
+            None => writeln!(s, "            if let Some(ref v) = self.{} {{\n                v.write_fix({}, h);\n            }}", field, tag)?,
+        }
+    }
+    writeln!(s, "        }}\n    }}\n")?;
+
+    let msg = match msg {
+        Some(msg) => msg,
+        None => return Ok(()),
+    };
+    let required: Vec<String> = fields.iter().filter(|f| f.3).map(|f| f.0.to_string()).collect();
+    writeln!(s, "    impl ::fix::FixTagHandler for {} {{", name)?;
+    writeln!(s, "        fn tag_value(&mut self, t: u32, v: &[u8]) {{\n            self.accept(t, v);\n        }}\n    }}\n")?;
+    writeln!(s, "    impl ::fix::FixStream for {} {{", name)?;
+    writeln!(s, "        type MSG_TYPES = super::MsgType;\n")?;
+    writeln!(s, "        fn fix_message_start(&mut self, msg_type: ::fix::FixMsgType<Self::MSG_TYPES>, is_replayable: bool) {{")?;
+    writeln!(s, "            *self = Default::default();")?;
+    writeln!(s, "            if msg_type.as_bytes() != b{:?} {{", msg.msg_type)?;
+    writeln!(s, "                self._state.done(Err(format!(\"Unexpected MsgType {{}}\", String::from_utf8_lossy(msg_type.as_bytes()))), &[]);")?;
+    writeln!(s, "            }}\n        }}\n")?;
+    writeln!(s, "        fn fix_message_done(&mut self, res: Result<(), ::fix::FixStreamException>) {{")?;
+    writeln!(s, "            self._state.done(res, &[{}]);\n        }}\n    }}\n", required.join(", "))?;
+    writeln!(s, "    impl ::message::FixMessage for {} {{", name)?;
+    writeln!(s, "        fn msg_type() -> &'static [u8] {{\n            b{:?}\n        }}\n", msg.msg_type)?;
+    writeln!(s, "        fn encode<S: ::fix::FixStream>(&self, s: &mut S) {{")?;
+    writeln!(s, "            s.fix_message_start(::fix::FixMsgType::from(&b{:?}[..]), {});", msg.msg_type, msg.msg_cat != "admin")?;
+    writeln!(s, "            self.encode_fields(s);\n            s.fix_message_done(Ok(()));\n        }}\n")?;
+    writeln!(s, "        fn decode_error(&self) -> Option<&::fix::FixStreamException> {{")?;
+    writeln!(s, "            self._state.error()\n        }}\n    }}\n")?;
+    Ok(())
+}
+
+const DICTIONARY_IMPL: &'static str = "
+/// Compiled in dictionary
+pub struct Dictionary;
+
+impl ::fix::FixDictionary for Dictionary {
+    fn group_delimiter(&self, no_tag: u32) -> Option<u32> { group_delimiter(no_tag) }
+    fn group_member(&self, no_tag: u32, tag: u32) -> bool { group_member(no_tag, tag) }
+    fn field_name(&self, tag: u32) -> Option<&str> { field_name(tag) }
+    fn value_name(&self, tag: u32, value: &[u8]) -> Option<&str> { value_name(tag, value) }
+    fn field_tag(&self, name: &str) -> Option<u32> { field_tag(name) }
+    fn is_header_field(&self, tag: u32) -> bool { is_header_field(tag) }
+}
+";
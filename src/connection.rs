@@ -1,147 +1,1451 @@
 use fix::*;
 use util::*;
+use fix_tags::{header, body, trailer};
+#[cfg(feature = "trace-messages")]
+use format::FixDisplay;
+use dictionary::{DataDictionary, ValidationError};
+use state::{SessionState, SessionEvent};
+use store::FixMessageStore;
+use schedule::SessionSchedule;
+use std::rc::Rc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Session diagnostics, printed with the trace-messages feature only.
+/// Arguments are type checked either way.
+#[cfg(feature = "trace-messages")]
+macro_rules! trace {
+    ($($arg:tt)*) => { println!($($arg)*) }
+}
+
+#[cfg(not(feature = "trace-messages"))]
+macro_rules! trace {
+    ($($arg:tt)*) => { if false { println!($($arg)*) } }
+}
 
 /// ///////////////////////////////////////////////////////////////
 /// Temporary
 /// ///////////////////////////////////////////////////////////////
 
 
+/// HeartBtInt(108) sent in the initiator's Logon unless app overrides it
+pub const DEFAULT_HEART_BT_INT: u32 = 30;
+
+/// How long to wait for the reply to our Logout
+pub const DEFAULT_LOGOUT_TIMEOUT_SECS: u64 = 10;
+
+/// Upper bound of the counterparty's HeartBtInt unless configured otherwise
+pub const DEFAULT_MAX_HEART_BT_INT: u32 = 3600;
+
+/// Resolution of the heartbeat timer, HeartBtInt is in seconds
+const HEARTBEAT_TICK_SECS: u64 = 1;
+
+/// When sequence numbers of both sides go back to 1
+#[derive(Debug, Clone, Default)]
+pub struct SeqResetPolicy {
+    /// Logon is sent (initiator) or answered (acceptor) with ResetSeqNumFlag(141)=Y
+    pub on_logon: bool,
+    /// once the logout handshake is over
+    pub on_logout: bool,
+    /// whenever the transport is closed
+    pub on_disconnect: bool,
+}
+
+/// Initiator reconnects after connection is lost (or could not be made)
+/// while inside the session schedule
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// wait before the first attempt, doubles with every failed one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// fraction of the wait taken off at random, spreads reconnecting sessions
+    pub jitter: f64,
+    /// primary first then backups, failed attempt moves on to the next one
+    pub endpoints: Vec<String>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy
+    {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            jitter: 0.2,
+            endpoints: vec![],
+        }
+    }
+}
+
+/// What becomes of an application message written while the session is
+/// not logged on (before logon completes, during logout or disconnected)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutboundPolicy {
+    /// dropped, the error is available from take_send_error
+    Reject,
+    /// held in memory and sent once logon completes
+    Queue,
+    /// takes the next sequence number and goes to the store without being
+    /// sent, counterparty recovers it with ResendRequest. Message that is
+    /// not replayable is gap filled instead. Does not survive a sequence
+    /// reset (ResetSeqNumFlag, reset policy or a new schedule period):
+    /// messages not re-sent by then are dropped and reported by take_send_error.
+    Persist,
+}
+
+/// Application message held until logon
+struct QueuedMessage {
+    msg_type: Vec<u8>,
+    is_replayable: bool,
+    fields: Vec<(u32, Vec<u8>)>,
+}
+
+/// What is being done with the message being written
+enum OutMode {
+    Send,
+    Reject(Vec<u8>),
+    Queue(QueuedMessage),
+    Persist,
+}
+
 #[derive(Debug)]
 pub enum ConnectionType {
     Initiator,
     Acceptor,
 }
 
-pub struct FixConnection<'a, T, E>
-    where T: 'a + FixTransport,
+pub struct FixConnection<T, E>
+    where T: FixTransport,
           E: FixTimerFactory
 {
     timers: E,
-    transport: Option<&'a mut T>,
-    in_state: FixInState,
-    out_state: FixOutState,
+    transport: T,
+    state: SessionState,
     conn_type: ConnectionType,
     nextInSeq: u32,
+    // messages received ahead of nextInSeq, None marks number already processed
+    queue: BTreeMap<u32, Option<Vec<u8>>>,
+    // end of the gap ResendRequest was sent for
+    resend_end: Option<u32>,
+    next_out_seq: u32,
+    // message being written keeps its original sequence number
+    resending: bool,
+    // message being written goes to the store once done
+    storing: bool,
+    store: Box<FixMessageStore>,
+    reset_policy: SeqResetPolicy,
+    // we sent Logon with ResetSeqNumFlag, counterparty's Logon is the reply
+    reset_requested: bool,
+    // counterparty reset mid-session, Logon with ResetSeqNumFlag is to be sent back
+    reset_reply_due: bool,
+    // NextExpectedMsgSeqNum(789) goes into our Logon
+    send_next_expected: bool,
+    // MsgSeqNum of our last Logon
+    logon_seq: u32,
+    // counterparty's NextExpectedMsgSeqNum, serviced once logon completes
+    peer_next_expected: Option<u32>,
+    begin_string: String,
+    // our SenderCompID and TargetCompID, not checked unless configured
+    comp_ids: Option<(String, String)>,
+    // negotiated HeartBtInt, 0 - no heartbeats
+    heart_bt_int: u32,
+    // HeartBtInt the counterparty may ask for, inclusive
+    heart_bt_int_bounds: (u32, u32),
+    heartbeat_timer: Option<Box<FixTimerHandler>>,
+    // timer ticks not accounted yet, timer runs outside of the connection
+    ticks: Arc<AtomicUsize>,
+    // seconds since last message received / sent
+    in_idle: u32,
+    out_idle: u32,
+    // Logout sent by us, waiting for the reply
+    logout_initiated: bool,
+    logout_timeout: Duration,
+    logout_timer: Option<Box<FixTimerHandler>>,
+    logout_expired: Arc<AtomicUsize>,
+    schedule: Option<SessionSchedule>,
+    clock: Box<FixClock>,
+    // start of the session period sequence numbers belong to
+    session_period: Option<SystemTime>,
+    schedule_timer: Option<Box<FixTimerHandler>>,
+    schedule_due: Arc<AtomicUsize>,
+    reconnect: Option<ReconnectPolicy>,
+    // index into reconnect endpoints
+    endpoint: usize,
+    // failed connect attempts since last connected
+    connect_attempts: u32,
+    // connection was lost, next one carries on the same session
+    reconnecting: bool,
+    reconnect_timer: Option<Box<FixTimerHandler>>,
+    reconnect_due: Arc<AtomicUsize>,
+    // jitter source
+    rng: u64,
+    // delivered to the app from connect and poll_reconnect
+    events: Vec<ConnectionEvent>,
+    outbound_policy: OutboundPolicy,
+    // per MsgType overrides
+    outbound_policies: HashMap<Vec<u8>, OutboundPolicy>,
+    out_mode: OutMode,
+    out_queue: VecDeque<QueuedMessage>,
+    // numbers taken by OutboundPolicy::Persist and not re-sent yet
+    persisted: BTreeSet<u32>,
+    send_error: Option<FixStreamException>,
+    // TestReqID of outstanding TestRequest
+    test_req_id: Option<String>,
+    test_req_count: u32,
     fix_writer: FixMessageWriter<()>,
+    dictionary: Option<Rc<DataDictionary>>,
 }
 
-impl<'a, T, E> FixConnection<'a, T, E>
+impl<T, E> FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
-    pub fn new(version: String, transport: &mut T, timers: E, conn_type: ConnectionType) -> FixConnection<'a, T, E> 
+    /// Sequence numbers are picked up from the store, the session
+    /// carries on where it stopped last time
+    pub fn new(version: String, transport: T, timers: E, conn_type: ConnectionType, store: Box<FixMessageStore>)
+        -> FixConnection<T, E>
     {
         FixConnection {
             timers: timers,
-            transport: Some(transport),
-            in_state: FixInState::Disconnected,
-            out_state: FixOutState::Disconnected,
+            transport: transport,
+            state: SessionState::new(),
             conn_type: conn_type,
-            nextInSeq: 0,
+            nextInSeq: store.next_target_seq(),
+            queue: BTreeMap::new(),
+            resend_end: None,
+            next_out_seq: store.next_sender_seq(),
+            resending: false,
+            storing: false,
+            store: store,
+            reset_policy: Default::default(),
+            reset_requested: false,
+            reset_reply_due: false,
+            send_next_expected: false,
+            logon_seq: 0,
+            peer_next_expected: None,
+            begin_string: version.clone(),
+            comp_ids: None,
+            heart_bt_int: 0,
+            heart_bt_int_bounds: (0, DEFAULT_MAX_HEART_BT_INT),
+            heartbeat_timer: None,
+            ticks: Arc::new(AtomicUsize::new(0)),
+            in_idle: 0,
+            out_idle: 0,
+            logout_initiated: false,
+            logout_timeout: Duration::from_secs(DEFAULT_LOGOUT_TIMEOUT_SECS),
+            logout_timer: None,
+            logout_expired: Arc::new(AtomicUsize::new(0)),
+            schedule: None,
+            clock: Box::new(SystemClock),
+            session_period: None,
+            schedule_timer: None,
+            schedule_due: Arc::new(AtomicUsize::new(0)),
+            reconnect: None,
+            endpoint: 0,
+            connect_attempts: 0,
+            reconnecting: false,
+            reconnect_timer: None,
+            reconnect_due: Arc::new(AtomicUsize::new(0)),
+            rng: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u64).unwrap_or(0) | 1,
+            events: vec![],
+            outbound_policy: OutboundPolicy::Reject,
+            outbound_policies: HashMap::new(),
+            out_mode: OutMode::Send,
+            out_queue: VecDeque::new(),
+            persisted: BTreeSet::new(),
+            send_error: None,
+            test_req_id: None,
+            test_req_count: 0,
             fix_writer: FixMessageWriter::new(version),
+            dictionary: None,
         }
     }
 
-    pub fn read_message<S>(&mut self, app: &mut S)
-        where S: FixApplication
+    /// Per session data dictionary, used to write groups and validate incoming messages
+    pub fn with_dictionary(mut self, dictionary: Rc<DataDictionary>) -> FixConnection<T, E>
+    {
+        self.fix_writer.set_dictionary(dictionary.clone());
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    /// Session identity, stamped on every outgoing message. Incoming messages
+    /// must carry them swapped: SenderCompID(49) is our target and vice versa
+    pub fn with_comp_ids(mut self, sender_comp_id: &str, target_comp_id: &str) -> FixConnection<T, E>
     {
-        match self._read_message(app) {
-            Ok(Some((size, request))) => {
-                println!("Success! {:?}", size);
-                self.transport.as_mut().unwrap().consume(size);
-                if request.is_some() {
-                    app.on_request(request.unwrap(), self);
+        self.comp_ids = Some((sender_comp_id.to_string(), target_comp_id.to_string()));
+        self
+    }
+
+    pub fn with_seq_reset_policy(mut self, policy: SeqResetPolicy) -> FixConnection<T, E>
+    {
+        self.reset_policy = policy;
+        self
+    }
+
+    /// Our Logon tells the next sequence number expected from the counterparty,
+    /// messages it has missed are then re-sent without waiting for ResendRequest
+    pub fn with_next_expected_msg_seq_num(mut self, enabled: bool) -> FixConnection<T, E>
+    {
+        self.send_next_expected = enabled;
+        self
+    }
+
+    /// HeartBtInt accepted in the counterparty's Logon
+    pub fn with_heart_bt_int_bounds(mut self, min: u32, max: u32) -> FixConnection<T, E>
+    {
+        self.heart_bt_int_bounds = (min, max);
+        self
+    }
+
+    /// How long to wait for the Logout reply before disconnecting
+    pub fn with_logout_timeout(mut self, timeout: Duration) -> FixConnection<T, E>
+    {
+        self.logout_timeout = timeout;
+        self
+    }
+
+    /// Session is up only within the schedule, see poll_schedule
+    pub fn with_schedule(mut self, schedule: SessionSchedule) -> FixConnection<T, E>
+    {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Time source for the schedule and SendingTime, system clock by default
+    pub fn with_clock(mut self, clock: Box<FixClock>) -> FixConnection<T, E>
+    {
+        self.clock = clock;
+        self
+    }
+
+    /// Initiator only, acceptor waits for the counterparty to come back
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> FixConnection<T, E>
+    {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Handling of application messages written while not logged on, Reject by default
+    pub fn with_outbound_policy(mut self, policy: OutboundPolicy) -> FixConnection<T, E>
+    {
+        self.outbound_policy = policy;
+        self
+    }
+
+    /// Overrides the outbound policy for one MsgType(35)
+    pub fn with_outbound_policy_for(mut self, msg_type: &[u8], policy: OutboundPolicy) -> FixConnection<T, E>
+    {
+        self.outbound_policies.insert(msg_type.to_vec(), policy);
+        self
+    }
+
+    /// Error of the last message dropped (OutboundPolicy::Reject, invalid groups or
+    /// persisted messages lost to a sequence reset), cleared once taken
+    pub fn take_send_error(&mut self) -> Option<FixStreamException>
+    {
+        self.send_error.take()
+    }
+
+    /// Number of messages waiting for logon
+    pub fn queued_messages(&self) -> usize
+    {
+        self.out_queue.len()
+    }
+
+    pub fn store(&self) -> &FixMessageStore
+    {
+        &*self.store
+    }
+
+    pub fn dictionary(&self) -> Option<&DataDictionary>
+    {
+        self.dictionary.as_ref().map(|d| &**d)
+    }
+
+    /// Timer factory the connection was created with
+    pub fn timers(&mut self) -> &mut E
+    {
+        &mut self.timers
+    }
+
+    /// Negotiated HeartBtInt, 0 if heartbeats are off
+    pub fn heart_bt_int(&self) -> u32
+    {
+        self.heart_bt_int
+    }
+
+    /// Accounts heartbeat timer ticks: sends Heartbeat when nothing was sent
+    /// for HeartBtInt, TestRequest when nothing was received for HeartBtInt
+    /// plus grace period and disconnects if TestRequest is not answered in time.
+    /// Driver is expected to call it periodically, read_message calls it too.
+    pub fn poll_timers(&mut self)
+    {
+        if self.logout_expired.swap(0, Ordering::SeqCst) > 0 && self.logout_initiated {
+            trace!("Logout reply not received in {:?}", self.logout_timeout);
+            self.disconnect();
+            return;
+        }
+
+        let ticks = self.ticks.swap(0, Ordering::SeqCst) as u32;
+        if ticks == 0 || self.heart_bt_int == 0 || !self.state.is_logged_on() {
+            return;
+        }
+        self.in_idle += ticks * HEARTBEAT_TICK_SECS as u32;
+        self.out_idle += ticks * HEARTBEAT_TICK_SECS as u32;
+
+        let timeout = self.heart_bt_int + heartbeat_grace(self.heart_bt_int);
+        if self.test_req_id.is_some() {
+            if self.in_idle >= 2 * timeout {
+                trace!("TestRequest {:?} not answered", self.test_req_id);
+                self.disconnect();
+                return;
+            }
+        } else if self.in_idle >= timeout {
+            self.test_req_count += 1;
+            let id = format!("TEST-{}-{}", utc_timestamp(self.clock.now()), self.test_req_count);
+            self.test_req_id = Some(id.clone());
+            self.send_request(&SessionRequestType::TestRequest(TestRequestFields { test_req_id: id }));
+        }
+
+        if self.out_idle >= self.heart_bt_int {
+            self.send_request(&SessionRequestType::Heartbeat(Default::default()));
+        }
+    }
+
+    /// Follows the schedule: starts the session once it is time, ends it
+    /// when the time is over and resets sequence numbers and the store when
+    /// a new session period begins (or the store is left from an earlier one).
+    /// Schedule is checked once its timer fires, the driver is expected to
+    /// call it periodically as well as poll_timers.
+    pub fn poll_schedule<L>(&mut self, l: &mut L)
+        where L: FixApplication
+    {
+        if self.schedule_due.swap(0, Ordering::SeqCst) == 0 && self.schedule_timer.is_some() {
+            return;
+        }
+        let now = self.clock.now();
+        let (period, next) = match self.schedule {
+            Some(ref schedule) => (schedule.period(now), schedule.next_change(now)),
+            None => return,
+        };
+
+        let disconnected = self.state.out_state() == FixOutState::Disconnected;
+        // logout takes a while, check again shortly
+        let mut pending = false;
+        match period {
+            Some((start, _)) if self.session_period != Some(start) => {
+                if disconnected {
+                    let stale = self.session_period.is_some() || self.store.creation_time() < start;
+                    if stale {
+                        self.reset_incoming(1);
+                        self.reset_outgoing();
+                    }
+                    self.session_period = Some(start);
+                    // new period is a fresh start rather than a reconnect
+                    self.reconnecting = false;
+                    self.connect_attempts = 0;
+                    self.start_session(l);
+                } else {
+                    // the previous period is still running
+                    self.end_session(l);
+                    pending = true;
+                }
+            },
+            Some(_) => {
+                if disconnected {
+                    self.start_session(l);
+                }
+            },
+            None => {
+                if !disconnected {
+                    self.end_session(l);
+                    pending = true;
+                }
+            },
+        }
+
+        let wait = match next {
+            Some(ref next) if !pending => next.duration_since(now).unwrap_or(Duration::from_secs(0)),
+            // no session ever, nothing to wait for but the schedule may be checked once a day
+            None if !pending => Duration::from_secs(86400),
+            _ => Duration::from_secs(0),
+        };
+        if let Some(timer) = self.schedule_timer.take() {
+            timer.cancel();
+        }
+        let due = self.schedule_due.clone();
+        let timer = self.timers.set_timeout(move || {
+            due.fetch_add(1, Ordering::SeqCst);
+        }, ::std::cmp::max(wait, Duration::from_secs(HEARTBEAT_TICK_SECS)));
+        self.schedule_timer = Some(timer);
+    }
+
+    /// Reconnects once the backoff is over, connection events
+    /// are delivered to the app from here
+    pub fn poll_reconnect<L>(&mut self, l: &mut L)
+        where L: FixApplication
+    {
+        if self.reconnect_due.swap(0, Ordering::SeqCst) > 0 {
+            self.cancel_reconnect();
+            if self.state.out_state() == FixOutState::Disconnected {
+                if self.is_session_time() {
+                    self.connect(l);
+                } else {
+                    // schedule starts the next session
+                    self.reconnecting = false;
+                    self.connect_attempts = 0;
                 }
             }
-            Ok(None) => {
-                /*
-                self.transport.as_mut().unwrap().on_read(|transport| {
-                    self.transport = Some(transport);
-                    println!("On read!");
-                });
-                */
+        }
+        self.emit_events(l);
+    }
+
+    /// Sets the timer for the next connect attempt, None if not to reconnect
+    fn schedule_reconnect(&mut self) -> Option<Duration>
+    {
+        let (ms, jitter) = match (&self.conn_type, &self.reconnect) {
+            (&ConnectionType::Initiator, &Some(ref policy)) => {
+                let mut wait = policy.initial_backoff;
+                // lost connection and the first failed attempt both wait initial_backoff
+                for _ in 0..self.connect_attempts.saturating_sub(1) {
+                    if wait >= policy.max_backoff {
+                        break;
+                    }
+                    wait = wait * 2;
+                }
+                let wait = ::std::cmp::min(wait, policy.max_backoff);
+                (wait.as_secs() * 1000 + wait.subsec_nanos() as u64 / 1000000, policy.jitter)
+            },
+            _ => return None,
+        };
+        let ms = ms - (ms as f64 * jitter.max(0.0).min(1.0) * self.random()) as u64;
+        let wait = Duration::from_millis(ms);
+
+        self.cancel_reconnect();
+        let due = self.reconnect_due.clone();
+        let timer = self.timers.set_timeout(move || {
+            due.fetch_add(1, Ordering::SeqCst);
+        }, wait);
+        self.reconnect_timer = Some(timer);
+        Some(wait)
+    }
+
+    fn cancel_reconnect(&mut self)
+    {
+        if let Some(timer) = self.reconnect_timer.take() {
+            timer.cancel();
+        }
+        self.reconnect_due.store(0, Ordering::SeqCst);
+    }
+
+    /// xorshift, in [0, 1)
+    fn random(&mut self) -> f64
+    {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn emit_events<L>(&mut self, l: &mut L)
+        where L: FixApplication
+    {
+        for event in ::std::mem::replace(&mut self.events, vec![]) {
+            l.on_connection_event(&event);
+        }
+    }
+
+    /// Whether logon is allowed now, always without schedule
+    pub fn is_session_time(&self) -> bool
+    {
+        self.schedule.as_ref().map_or(true, |s| s.is_active(self.clock.now()))
+    }
+
+    fn start_heartbeat(&mut self)
+    {
+        self.stop_heartbeat();
+        if self.heart_bt_int == 0 {
+            return;
+        }
+        self.in_idle = 0;
+        self.out_idle = 0;
+        let ticks = self.ticks.clone();
+        let timer = self.timers.set_timeout(move || {
+            ticks.fetch_add(1, Ordering::SeqCst);
+        }, Duration::from_secs(HEARTBEAT_TICK_SECS));
+        self.heartbeat_timer = Some(timer);
+    }
+
+    /// Logout sent, reply is expected within logout timeout
+    fn logout_sent(&mut self)
+    {
+        self.transition(SessionEvent::LogoutSent);
+        if self.logout_initiated {
+            return;
+        }
+        self.logout_initiated = true;
+        let expired = self.logout_expired.clone();
+        let timer = self.timers.set_timeout(move || {
+            expired.fetch_add(1, Ordering::SeqCst);
+        }, self.logout_timeout);
+        self.logout_timer = Some(timer);
+    }
+
+    fn stop_heartbeat(&mut self)
+    {
+        if let Some(timer) = self.heartbeat_timer.take() {
+            timer.cancel();
+        }
+        self.ticks.store(0, Ordering::SeqCst);
+        self.test_req_id = None;
+    }
+
+    /// Session state machine, its log records every transition made
+    pub fn session_state(&self) -> &SessionState
+    {
+        &self.state
+    }
+
+    /// Applies the event, illegal transition terminates the session
+    fn transition(&mut self, event: SessionEvent)
+    {
+        if let Err(e) = self.state.apply(event) {
+            trace!("{}", e);
+            self.disconnect();
+        }
+    }
+
+    fn disconnect(&mut self)
+    {
+        let logged_out = self.state.out_state() == FixOutState::Logout;
+        let lost = !logged_out && self.state.out_state() != FixOutState::Disconnected;
+        self.reset_requested = false;
+        self.reset_reply_due = false;
+        self.peer_next_expected = None;
+        self.stop_heartbeat();
+        if let Some(timer) = self.logout_timer.take() {
+            timer.cancel();
+        }
+        self.logout_expired.store(0, Ordering::SeqCst);
+        self.logout_initiated = false;
+        self.transport.disconnect();
+        self.transition(SessionEvent::Disconnected);
+        // store failing here has nothing more to disconnect
+        if self.reset_policy.on_disconnect || (logged_out && self.reset_policy.on_logout) {
+            self.reset_incoming(1);
+            self.reset_outgoing();
+        }
+        if lost && self.is_session_time() {
+            if let Some(wait) = self.schedule_reconnect() {
+                self.reconnecting = true;
+                self.events.push(ConnectionEvent::ConnectionLost { retry_in: wait });
             }
+        }
+    }
+
+    /// Counterparty starts over from `seq`, anything queued or requested is void
+    fn reset_incoming(&mut self, seq: u32)
+    {
+        self.nextInSeq = seq;
+        self.queue.clear();
+        self.resend_end = None;
+    }
+
+    /// Our numbers start over from 1, stored messages can not be re-sent anymore
+    fn reset_outgoing(&mut self)
+    {
+        if !self.persisted.is_empty() {
+            let e = format!("{} persisted message(s) dropped by sequence reset, first MsgSeqNum {}",
+                            self.persisted.len(), self.persisted.iter().next().unwrap());
+            self.send_error = Some(e);
+            self.persisted.clear();
+        }
+        self.next_out_seq = 1;
+        if let Err(e) = self.store.reset() {
+            self.store_failed(e);
+        }
+        self.save_seqs();
+    }
+
+    /// Keeps the store up to date with the sequence numbers of both sides,
+    /// false if the store failed (the session is disconnected then)
+    fn save_seqs(&mut self) -> bool
+    {
+        let mut res = Ok(());
+        if self.store.next_sender_seq() != self.next_out_seq {
+            res = self.store.set_next_sender_seq(self.next_out_seq);
+        }
+        if res.is_ok() && self.store.next_target_seq() != self.nextInSeq {
+            res = self.store.set_next_target_seq(self.nextInSeq);
+        }
+        match res {
+            Ok(()) => true,
             Err(e) => {
-                // shutdown
-                println!("Error! {:?}", e);
-            }
+                self.store_failed(e);
+                false
+            },
         }
     }
 
-    pub fn _read_message<S>(&mut self, app: &mut S) -> Result<Option<(usize, Option<SessionRequest>)>, FixStreamException>
-        where S: FixApplication
+    /// Session can not go on without its state persisted
+    fn store_failed(&mut self, e: FixStreamException)
     {
-        let mut request = None;
-
-        let mut t = self.transport.as_ref().unwrap();
-        
-        println!("Read message buf: {:?}", String::from_utf8_lossy(t.view()));
-        
-        let mut chksum = 0;
-        let mut s = super::util::Slicer {buf: t.view(), len: 0};
-        let required_tags = [8, 9, 35];
-        
-        for eid in &required_tags[..] {
-            //println!("total: {:?}", String::from_utf8_lossy(s.buf()));
-            let res = super::util::get_tag(&mut s);
-            match res {
-                Ok(Some((id, _, _))) if id != *eid as u32 => {
-                    return Err(format!("Missplaced tag expected {} got {}", *eid, id));
+        trace!("Store error: {}", e);
+        if self.state.out_state() != FixOutState::Disconnected {
+            self.disconnect();
+        }
+    }
+
+    /// Services ResendRequest: stored messages are replayed with PossDupFlag,
+    /// everything else in the range is replaced with SequenceReset-GapFill
+    fn resend(&mut self, begin: u32, end: u32)
+    {
+        // next_out_seq may have been forced to 0
+        let last = self.next_out_seq.saturating_sub(1);
+        if last == 0 {
+            // nothing sent yet
+            return;
+        }
+        let end = if end == 0 || end > last || (end == 999999 && self.infinity_999999()) { last } else { end };
+        if begin == 0 || begin > end {
+            return;
+        }
+        // end < next_out_seq, so end + 1 below does not overflow
+
+        self.transition(SessionEvent::ResendStarted);
+        let resent: Vec<u32> = self.persisted.range(begin..end + 1).cloned().collect();
+        for seq in resent {
+            self.persisted.remove(&seq);
+        }
+        let mut gap_start = None;
+        for seq in begin..end + 1 {
+            match self.store.get(seq) {
+                Some(msg) => {
+                    if let Some(gap) = gap_start.take() {
+                        self.send_gap_fill(gap, seq);
+                    }
+                    self.replay(seq, &msg);
                 },
-                Ok(Some((id, v, sum))) if id == 35u32 => {
-                    chksum += sum;
-                    let msg_type = FixMsgType::from(v);
-                    request = Option::<SessionRequestType>::from(&msg_type);
-                    if request.is_none() {
-                        app.in_stream().fix_message_start(msg_type, true);
+                None => {
+                    if gap_start.is_none() {
+                        gap_start = Some(seq);
                     }
                 },
-                Ok(Some((id, _, sum))) => {
-                    chksum += sum;
+            }
+        }
+        if let Some(gap) = gap_start {
+            self.send_gap_fill(gap, end + 1);
+        }
+        self.transition(SessionEvent::ResendDone);
+    }
+
+    /// Up to FIX.4.2 EndSeqNo=999999 stands for "infinity"
+    fn infinity_999999(&self) -> bool
+    {
+        match &self.begin_string[..] {
+            "FIX.4.0" | "FIX.4.1" | "FIX.4.2" => true,
+            _ => false,
+        }
+    }
+
+    /// Re-sends stored message under its original number,
+    /// original SendingTime goes to OrigSendingTime
+    fn replay(&mut self, seq: u32, msg: &[u8])
+    {
+        let mut s = Slicer {buf: msg, len: 0};
+        while let Ok(Some((id, v, _))) = get_tag(&mut s) {
+            match id {
+                header::MsgType => {
+                    self.start_message(FixMsgType::from(v), Some(seq));
+                    self.fix_writer.tag_value(header::PossDupFlag, b"Y");
                 },
-                Ok(None) => return Ok(None),
-                Err(err) => return Err(err),
+                header::SendingTime => {
+                    self.fix_writer.tag_value(header::OrigSendingTime, v);
+                },
+                header::Version | header::Length | header::MsgSeqNum |
+                header::SenderCompID | header::TargetCompID |
+                header::PossDupFlag | header::PossResend | header::OrigSendingTime => {},
+                trailer::CheckSum => break,
+                _ => self.fix_writer.tag_value(id, v),
+            }
+        }
+        self.fix_message_done(Ok(()));
+    }
+
+    fn is_acceptor(&self) -> bool
+    {
+        match self.conn_type {
+            ConnectionType::Acceptor => true,
+            ConnectionType::Initiator => false,
+        }
+    }
+
+    /// Starts outgoing message with the standard header. Message re-sent
+    /// with its original sequence number provides `seq`, otherwise the next
+    /// outgoing sequence number is used and consumed once message is done.
+    fn start_message(&mut self, msg_type: FixMsgType<()>, seq: Option<u32>)
+    {
+        self.out_mode = OutMode::Send;
+        self.resending = seq.is_some();
+        self.storing = false;
+        let seq = seq.unwrap_or(self.next_out_seq);
+        self.fix_writer.fix_message_start(msg_type, false);
+        if let Some((ref sender, ref target)) = self.comp_ids {
+            self.fix_writer.tag_value(header::SenderCompID, sender.as_bytes());
+            self.fix_writer.tag_value(header::TargetCompID, target.as_bytes());
+        }
+        self.fix_writer.tag_value(header::MsgSeqNum, seq.to_string().as_bytes());
+        self.fix_writer.tag_value(header::SendingTime, utc_timestamp(self.clock.now()).as_bytes());
+    }
+
+    /// Writes session message carrying the request
+    fn send_request(&mut self, r: &SessionRequestType)
+    {
+        self.start_message(r.msg_type(), None);
+        r.write(self);
+        self.fix_message_done(Ok(()));
+        if let SessionRequestType::SeqReset(ref reset) = *r {
+            if !reset.gap_fill_flag {
+                // reset mode, counterparty expects NewSeqNo next
+                self.next_out_seq = reset.new_seq_no;
+                self.save_seqs();
+            }
+        }
+    }
+
+    /// Sends our Logon, NextExpectedMsgSeqNum is filled in if enabled
+    fn send_logon(&mut self, mut logon: LogonFields)
+    {
+        if self.send_next_expected {
+            let next = if logon.reset_seq_num_flag && self.reset_requested { 1 } else { self.nextInSeq };
+            logon.next_expected_msg_seq_num = Some(next);
+        }
+        self.logon_seq = self.next_out_seq;
+        self.send_request(&SessionRequestType::Logon(logon));
+    }
+
+    /// Sends SequenceReset-GapFill in place of message `seq`,
+    /// used instead of messages that are not to be re-sent
+    pub fn send_gap_fill(&mut self, seq: u32, new_seq_no: u32)
+    {
+        // taken first so that it's never after SendingTime
+        let orig_sending_time = utc_timestamp(self.clock.now());
+        self.start_message(FixMsgType::SeqReset, Some(seq));
+        self.fix_writer.tag_value(header::PossDupFlag, b"Y");
+        self.fix_writer.tag_value(header::OrigSendingTime, orig_sending_time.as_bytes());
+        SessionRequestType::SeqReset(SeqResetFields { gap_fill_flag: true, new_seq_no: new_seq_no }).write(self);
+        self.fix_message_done(Ok(()));
+    }
+
+    pub fn read_message<S>(&mut self, app: &mut S)
+        where S: FixApplication
+    {
+        if let FixInState::Disconnected = self.state.in_state() {
+            return;
+        }
+        self.poll_timers();
+        if let FixInState::Disconnected = self.state.in_state() {
+            return;
+        }
+
+        #[cfg(feature = "trace-messages")]
+        println!("Read message buf:\n{}", FixDisplay(self.transport.view()));
+
+        let header = match scan_message(self.transport.view()) {
+            Ok(Some(header)) => header,
+            Ok(None) => return,
+            Err(e) => {
+                // no way to find where the next message starts
+                println!("Error! {:?}", e);
+                self.disconnect();
+                return;
+            }
+        };
+
+        // any message proves the other side is alive,
+        // TestRequest is only answered by Heartbeat with its TestReqID
+        self.in_idle = 0;
+
+        let action = match self.check_session(&header) {
+            Some(action) => action,
+            None => self.check_seq(&header),
+        };
+        match action {
+            InAction::Deliver => {
+                let valid = validate(self.transport.view(), &header, self.dictionary.as_ref().map(|d| &**d));
+                match valid {
+                    Ok(()) => {
+                        let request = deliver(self.transport.view(), app);
+                        self.transport.consume(header.len);
+                        self.processed(&header, request, app);
+                    },
+                    Err(e) => {
+                        self.transport.consume(header.len);
+                        self.rejected(&header, e, app);
+                    },
+                }
+            },
+            InAction::Queue(seq) => {
+                let buf = self.transport.view()[..header.len].to_vec();
+                self.transport.consume(header.len);
+                self.queue.insert(seq, Some(buf));
+            },
+            InAction::Ignore => {
+                self.transport.consume(header.len);
+            },
+            InAction::Reject(e) => {
+                self.transport.consume(header.len);
+                self.send_reject(&header, &e);
+                self.logout_and_disconnect(e.text);
+            },
+            InAction::Logout(text) => {
+                self.transport.consume(header.len);
+                self.logout_and_disconnect(text);
+            },
+            InAction::Refuse(reason) => {
+                self.transport.consume(header.len);
+                self.logout_with(reason);
+            },
+        }
+
+        self.drain_queue(app);
+        self.check_gap();
+        self.save_seqs();
+    }
+
+    /// Session level checks done before the sequence number is looked at:
+    /// the first message has to be Logon, BeginString and CompIDs must match
+    fn check_session(&self, h: &InHeader) -> Option<InAction>
+    {
+        if h.begin_string != self.begin_string.as_bytes() {
+            return Some(InAction::Logout(format!("Incorrect BeginString {}, expecting {}",
+                                                 String::from_utf8_lossy(&h.begin_string), self.begin_string)));
+        }
+
+        match self.state.in_state() {
+            FixInState::Logon => {
+                if !h.is(FixMsgType::Logon) && !h.is(FixMsgType::Logout) {
+                    return Some(InAction::Logout(format!("First message is not Logon: {}",
+                                                         String::from_utf8_lossy(&h.msg_type))));
+                }
+            },
+            FixInState::Connected => {
+                // only sequence reset may come with another Logon
+                if h.is(FixMsgType::Logon) && !h.reset_seq_num {
+                    return Some(InAction::Logout(String::from("Logon received while logged on")));
+                }
+            },
+            _ => {},
+        }
+
+        if h.is(FixMsgType::Logon) && !self.is_session_time() {
+            return Some(InAction::Refuse(LogoutFields::rejected(SessionStatus::LogonsAreNotAllowedAtThisTime,
+                                                                "Logon outside of session time")));
+        }
+
+        if let Some((ref sender, ref target)) = self.comp_ids {
+            let bad = if h.sender_comp_id.as_ref().map(|v| &v[..]) != Some(target.as_bytes()) {
+                Some((header::SenderCompID, &h.sender_comp_id))
+            } else if h.target_comp_id.as_ref().map(|v| &v[..]) != Some(sender.as_bytes()) {
+                Some((header::TargetCompID, &h.target_comp_id))
+            } else {
+                None
             };
+            if let Some((tag, value)) = bad {
+                let text = format!("Incorrect CompID {}={}", tag,
+                                   value.as_ref().map(|v| String::from_utf8_lossy(v).into_owned()).unwrap_or_default());
+                if h.is(FixMsgType::Logon) {
+                    // no session to reject within
+                    return Some(InAction::Logout(text));
+                }
+                return Some(InAction::Reject(ValidationError {
+                    reason: SessionRejectReason::CompIdProblem,
+                    tag: Some(tag),
+                    text: text,
+                }));
+            }
+        }
+        None
+    }
+
+    /// Decides what to do with the incoming message based on its MsgSeqNum
+    fn check_seq(&mut self, h: &InHeader) -> InAction
+    {
+        let seq = match h.seq {
+            Some(seq) => seq,
+            None => return InAction::Logout(String::from("MsgSeqNum(34) missing")),
+        };
+
+        if (h.is(FixMsgType::SeqReset) && !h.gap_fill) || (h.is(FixMsgType::Logon) && h.reset_seq_num) {
+            // reset mode ignores sequencing
+            return InAction::Deliver;
+        }
+
+        if seq == self.nextInSeq {
+            InAction::Deliver
+        } else if seq > self.nextInSeq {
+            if h.is(FixMsgType::Logon) {
+                // logon is processed right away, its number is skipped once the gap is filled
+                self.queue.insert(seq, None);
+                InAction::Deliver
+            } else {
+                InAction::Queue(seq)
+            }
+        } else if h.poss_dup {
+            // already seen
+            InAction::Ignore
+        } else {
+            InAction::Logout(format!("MsgSeqNum too low, expecting {} but received {}", self.nextInSeq, seq))
+        }
+    }
+
+    /// Message delivered, expected sequence number advances
+    /// and session request (if any) goes to the application
+    fn processed<S>(&mut self, h: &InHeader, request: Option<SessionRequestType>, app: &mut S)
+        where S: FixApplication
+    {
+        match request {
+            Some(SessionRequestType::SeqReset(ref reset)) => self.seq_reset(h, reset),
+            Some(SessionRequestType::Logon(ref logon)) if logon.reset_seq_num_flag => {
+                self.reset_incoming(h.seq.unwrap_or(1) + 1);
+                if self.reset_requested {
+                    self.reset_requested = false;
+                } else {
+                    // counterparty's initiative, our side follows
+                    self.reset_outgoing();
+                    self.reset_reply_due = self.state.is_logged_on();
+                }
+            },
+            _ => {
+                if h.seq == Some(self.nextInSeq) {
+                    self.nextInSeq += 1;
+                }
+            },
+        }
+        if let Some(SessionRequestType::Heartbeat(ref hb)) = request {
+            if hb.test_req_id.is_some() && hb.test_req_id == self.test_req_id {
+                self.test_req_id = None;
+            }
+        }
+        if let Some(SessionRequestType::Logon(ref logon)) = request {
+            let (min, max) = self.heart_bt_int_bounds;
+            if logon.heart_bt_int < min || logon.heart_bt_int > max {
+                self.logout_and_disconnect(format!("HeartBtInt {} out of range [{}, {}]", logon.heart_bt_int, min, max));
+                return;
+            }
+            if let Some(next) = logon.next_expected_msg_seq_num {
+                // counterparty can not have seen what we have not sent yet
+                if next > self.next_out_seq {
+                    self.logout_and_disconnect(format!("NextExpectedMsgSeqNum {} is higher than expected {}",
+                                                       next, self.next_out_seq));
+                    return;
+                }
+                self.peer_next_expected = Some(next);
+            }
+        }
+        if let Some(SessionRequestType::Logout(_)) = request {
+            self.transition(SessionEvent::LogoutReceived);
+        }
+        if let Some(ref r) = request {
+            app.on_request(SessionRequest::In(r.clone()), self);
+        }
+        if let FixOutState::Disconnected = self.state.out_state() {
+            return;
+        }
+        // mandatory replies, the application is only notified of these
+        match request {
+            Some(SessionRequestType::TestRequest(test)) => {
+                let hb = HeartbeatFields { test_req_id: Some(test.test_req_id) };
+                self.send_request(&SessionRequestType::Heartbeat(hb));
+            },
+            Some(SessionRequestType::ResendRequest(resend)) => {
+                self.resend(resend.begin_seq_no, resend.end_seq_no);
+            },
+            Some(SessionRequestType::Logout(_)) => {
+                if !self.logout_initiated {
+                    // counterparty logs out, confirm and close
+                    self.send_request(&SessionRequestType::Logout(Default::default()));
+                    self.transition(SessionEvent::LogoutSent);
+                }
+                // otherwise it's the reply to our Logout, handshake is complete
+                self.disconnect();
+            },
+            _ => {},
+        }
+    }
+
+    /// Applies incoming SequenceReset. GapFill has passed the sequence check
+    /// already, Reset forces the number regardless of MsgSeqNum.
+    /// Either way NewSeqNo can only move expected number forward.
+    fn seq_reset(&mut self, h: &InHeader, reset: &SeqResetFields)
+    {
+        if reset.new_seq_no > self.nextInSeq {
+            self.nextInSeq = reset.new_seq_no;
+        } else if reset.new_seq_no < self.nextInSeq {
+            let text = format!("NewSeqNo {} is lower than expected {}", reset.new_seq_no, self.nextInSeq);
+            if reset.gap_fill_flag {
+                // the gap fill itself is consumed
+                self.nextInSeq += 1;
+            }
+            self.send_reject(h, &ValidationError {
+                reason: SessionRejectReason::ValueIsIncorrect,
+                tag: Some(body::NewSeqNo),
+                text: text,
+            });
+        }
+    }
+
+    /// Answers invalid message with Reject, its sequence number is consumed
+    /// nevertheless. Invalid Logon can not establish the session.
+    fn rejected<S>(&mut self, h: &InHeader, e: ValidationError, app: &mut S)
+        where S: FixApplication
+    {
+        if h.is(FixMsgType::Logon) {
+            self.logout_and_disconnect(e.text);
+            return;
         }
+        let reject = self.send_reject(h, &e);
+        if h.seq == Some(self.nextInSeq) {
+            self.nextInSeq += 1;
+        }
+        app.on_session_reject(&reject, &e);
+    }
 
+    /// Sends session level Reject referring to the incoming message,
+    /// FIX.4.0 and FIX.4.1 have no RefTagID, RefMsgType and SessionRejectReason
+    fn send_reject(&mut self, h: &InHeader, e: &ValidationError) -> RejectFields
+    {
+        let mut reject = RejectFields {
+            ref_seq_num: h.seq.unwrap_or(0),
+            ref_tag_id: e.tag,
+            ref_msg_type: Some(String::from_utf8_lossy(&h.msg_type).into_owned()),
+            session_reject_reason: Some(e.reason.code()),
+            text: Some(e.text.clone()),
+        };
+        match &self.begin_string[..] {
+            "FIX.4.0" | "FIX.4.1" => {
+                let mut sent = reject.clone();
+                sent.ref_tag_id = None;
+                sent.ref_msg_type = None;
+                sent.session_reject_reason = None;
+                self.send_request(&SessionRequestType::Reject(sent));
+            },
+            _ => self.send_request(&SessionRequestType::Reject(reject.clone())),
+        }
+        reject
+    }
+
+    /// Delivers queued messages that are next in sequence
+    fn drain_queue<S>(&mut self, app: &mut S)
+        where S: FixApplication
+    {
         loop {
-            //println!("tags: {:?}", String::from_utf8_lossy(s.buf()));
-            match super::util::get_tag(&mut s) {
-                Ok(Some((id, v, sum))) if id != 10u32 => {
-                    chksum += sum;
-                    println!("tag: {} {:?}", id, String::from_utf8_lossy(v));
-                    if request.is_none() {
-                        app.in_stream().tag_value(id, v);
-                    }
-                },
-                Ok(Some((_, v, _))) => {
-                    let sum = v.iter().fold(0, |acc: u32, &x| acc * 10 + (x as u32 -'0' as u32));
-                    if sum == (chksum % 256) { 
-                        if request.is_none() {
-                            app.in_stream().fix_message_done(Ok(()));
+            let next = self.nextInSeq;
+            match self.queue.remove(&next) {
+                Some(Some(buf)) => {
+                    if let Ok(Some(header)) = scan_message(&buf) {
+                        match validate(&buf, &header, self.dictionary.as_ref().map(|d| &**d)) {
+                            Ok(()) => {
+                                let request = deliver(&buf, app);
+                                self.processed(&header, request, app);
+                            },
+                            Err(e) => self.rejected(&header, e, app),
                         }
-                        return Ok(Some((s.len, request.and_then(|v| Some(SessionRequest::In(v))))));
-                    }
-                    let err_str = format!("Malformed message: calc sum {} != {}", chksum%256, sum);
-                    if request.is_none() {
-                        app.in_stream().fix_message_done(Err(err_str));
                     }
-                    let err_str = format!("Malformed message: calc sum {} != {}", chksum%256, sum);
-                    return Err(err_str);
                 },
-                Err(err) => {
-                    return Err(err)
-                },
-                Ok(None) => {
-                    return Ok(None)
+                Some(None) => {
+                    self.nextInSeq += 1;
                 },
+                None => break,
+            }
+            if let FixInState::Disconnected = self.state.in_state() {
+                return;
             }
         }
+        // whatever is left below expected is not needed anymore
+        let next = self.nextInSeq;
+        self.queue = self.queue.split_off(&next);
+    }
+
+    /// Requests resend of the messages missing in front of the queue
+    fn check_gap(&mut self)
+    {
+        if let Some(end) = self.resend_end {
+            if self.nextInSeq > end {
+                self.resend_end = None;
+                if let FixOutState::Lagging = self.state.out_state() {
+                    self.transition(SessionEvent::GapFilled);
+                }
+            }
+        }
+
+        if self.resend_end.is_some() || !self.state.is_logged_on() {
+            return;
+        }
+        let first = match self.queue.keys().next() {
+            Some(&first) if first > self.nextInSeq => first,
+            _ => return,
+        };
+        let resend = ResendRequestFields { begin_seq_no: self.nextInSeq, end_seq_no: first - 1 };
+        self.send_request(&SessionRequestType::ResendRequest(resend));
+        self.resend_end = Some(first - 1);
+        self.transition(SessionEvent::GapDetected);
+    }
+
+    /// Sends application messages held until logon, in the order written
+    fn send_queued(&mut self)
+    {
+        while let Some(msg) = self.out_queue.pop_front() {
+            self.fix_message_start(FixMsgType::from(&msg.msg_type[..]), msg.is_replayable);
+            for &(t, ref v) in &msg.fields {
+                self.tag_value(t, v);
+            }
+            self.fix_message_done(Ok(()));
+        }
+    }
+
+    fn logout_and_disconnect(&mut self, text: String)
+    {
+        trace!("Logout: {}", text);
+        self.logout_with(LogoutFields { text: Some(text), session_status: None });
+    }
+
+    fn logout_with(&mut self, reason: LogoutFields)
+    {
+        self.send_request(&SessionRequestType::Logout(reason));
+        self.transition(SessionEvent::LogoutSent);
+        self.disconnect();
+    }
+}
+
+/// Transmission allowance on top of HeartBtInt before the other side is tested
+fn heartbeat_grace(heart_bt_int: u32) -> u32
+{
+    ::std::cmp::max(1, heart_bt_int / 5)
+}
+
+/// Fields the session acts upon before the message is delivered
+struct InHeader {
+    // length of the whole message
+    len: usize,
+    begin_string: Vec<u8>,
+    msg_type: Vec<u8>,
+    sender_comp_id: Option<Vec<u8>>,
+    target_comp_id: Option<Vec<u8>>,
+    seq: Option<u32>,
+    poss_dup: bool,
+    sending_time: Option<Vec<u8>>,
+    orig_sending_time: Option<Vec<u8>>,
+    // SequenceReset mode
+    gap_fill: bool,
+    // Logon resetting sequence numbers
+    reset_seq_num: bool,
+    // unreadable field, message is rejected
+    error: Option<ValidationError>,
+}
+
+impl InHeader {
+    fn is(&self, msg_type: FixMsgType<()>) -> bool
+    {
+        &self.msg_type[..] == msg_type.as_bytes()
+    }
+}
+
+enum InAction {
+    Deliver,
+    /// ahead of expected, held until the gap is filled
+    Queue(u32),
+    Ignore,
+    /// reject and terminate the session, i.e. CompID problem
+    Reject(ValidationError),
+    Logout(String),
+    /// Logout with SessionStatus(1409), i.e. logon outside of session time
+    Refuse(LogoutFields),
+}
+
+/// Reads the header of the first message in the buffer and validates the checksum,
+/// nothing is delivered at this point
+fn scan_message(buf: &[u8]) -> Result<Option<InHeader>, FixStreamException>
+{
+    let mut h = InHeader {
+        len: 0,
+        begin_string: vec![],
+        msg_type: vec![],
+        sender_comp_id: None,
+        target_comp_id: None,
+        seq: None,
+        poss_dup: false,
+        sending_time: None,
+        orig_sending_time: None,
+        gap_fill: false,
+        reset_seq_num: false,
+        error: None,
+    };
+    let mut chksum = 0;
+    let mut s = Slicer {buf: buf, len: 0};
+
+    for eid in &[header::Version, header::Length, header::MsgType] {
+        match get_tag(&mut s)? {
+            Some((id, _, _)) if id != *eid => {
+                return Err(format!("Missplaced tag expected {} got {}", *eid, id));
+            },
+            Some((id, v, sum)) => {
+                chksum += sum;
+                match id {
+                    header::Version => h.begin_string = v.to_vec(),
+                    header::MsgType => h.msg_type = v.to_vec(),
+                    _ => {},
+                }
+            },
+            None => return Ok(None),
+        }
+    }
+
+    loop {
+        match get_tag(&mut s) {
+            Ok(Some((id, v, _))) if id == trailer::CheckSum => {
+                let sum = parse_u32(v).unwrap_or(256);
+                if sum != chksum % 256 {
+                    return Err(format!("Malformed message: calc sum {} != {}", chksum % 256, sum));
+                }
+                h.len = s.len;
+                return Ok(Some(h));
+            },
+            Ok(Some((id, v, sum))) => {
+                chksum += sum;
+                match id {
+                    header::MsgSeqNum => h.seq = parse_u32(v),
+                    header::SenderCompID => h.sender_comp_id = Some(v.to_vec()),
+                    header::TargetCompID => h.target_comp_id = Some(v.to_vec()),
+                    header::PossDupFlag => h.poss_dup = v == b"Y",
+                    header::SendingTime => h.sending_time = Some(v.to_vec()),
+                    header::OrigSendingTime => h.orig_sending_time = Some(v.to_vec()),
+                    body::GapFillFlag => h.gap_fill = v == b"Y",
+                    body::ResetSeqNumFlag => h.reset_seq_num = v == b"Y",
+                    _ => {},
+                }
+            },
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                // skip the field, message boundary is still known
+                let rest = s.buf();
+                match rest.iter().position(|&b| b == b'\x01') {
+                    Some(pos) => {
+                        chksum += rest[..pos + 1].iter().map(|&b| b as u32).sum::<u32>();
+                        s.consume(pos + 1);
+                        if h.error.is_none() {
+                            h.error = Some(ValidationError {
+                                reason: SessionRejectReason::InvalidTagNumber,
+                                tag: None,
+                                text: e,
+                            });
+                        }
+                    },
+                    None => return Ok(None),
+                }
+            },
+        }
     }
 }
 
-impl<'a, T, E> FixService for FixConnection<'a, T, E>
+/// Required fields of session messages, checked even without data dictionary
+const SESSION_REQUIRED: &'static [(&'static [u8], &'static [u32])] = &[
+    (b"A", &[body::HeartBtInt]),
+    (b"1", &[body::TestReqID]),
+    (b"2", &[body::BeginSeqNo, body::EndSeqNo]),
+    (b"4", &[body::NewSeqNo]),
+    (b"3", &[body::RefSeqNum]),
+];
+
+/// Integer fields of session messages
+const SESSION_NUMERIC: &'static [u32] = &[
+    body::BeginSeqNo, body::EndSeqNo, body::NewSeqNo, body::RefSeqNum,
+    body::EncryptMethod, body::HeartBtInt,
+];
+
+/// Checks the message before it is delivered: fields the session relies on
+/// and, if the session has one, the data dictionary
+fn validate(buf: &[u8], h: &InHeader, dictionary: Option<&DataDictionary>) -> Result<(), ValidationError>
+{
+    if let Some(ref e) = h.error {
+        return Err(e.clone());
+    }
+    let buf = &buf[..h.len];
+    let mut tags = vec![];
+    let mut s = Slicer {buf: buf, len: 0};
+    while let Ok(Some((id, v, _))) = get_tag(&mut s) {
+        if id == 0 {
+            return Err(ValidationError { reason: SessionRejectReason::InvalidTagNumber, tag: Some(id),
+                                         text: String::from("Invalid tag number 0") });
+        }
+        if v.is_empty() {
+            return Err(ValidationError { reason: SessionRejectReason::TagSpecifiedWithoutValue, tag: Some(id),
+                                         text: format!("Tag {} specified without value", id) });
+        }
+        if SESSION_NUMERIC.contains(&id) && parse_u32(v).is_none() {
+            return Err(ValidationError { reason: SessionRejectReason::IncorrectDataFormat, tag: Some(id),
+                                         text: format!("Incorrect data format for tag {}", id) });
+        }
+        tags.push(id);
+    }
+    if h.poss_dup && h.orig_sending_time.is_none() {
+        return Err(ValidationError { reason: SessionRejectReason::RequiredTagMissing, tag: Some(header::OrigSendingTime),
+                                     text: String::from("OrigSendingTime(122) missing on PossDupFlag(43)=Y message") });
+    }
+    if let (true, &Some(ref orig), &Some(ref sending)) = (h.poss_dup, &h.orig_sending_time, &h.sending_time) {
+        if orig > sending {
+            return Err(ValidationError { reason: SessionRejectReason::SendingTimeAccuracyProblem, tag: Some(header::OrigSendingTime),
+                                         text: String::from("OrigSendingTime(122) is after SendingTime(52)") });
+        }
+    }
+    if let Some(&(_, required)) = SESSION_REQUIRED.iter().find(|&&(t, _)| &h.msg_type[..] == t) {
+        if let Some(&tag) = required.iter().find(|t| !tags.contains(t)) {
+            return Err(ValidationError { reason: SessionRejectReason::RequiredTagMissing, tag: Some(tag),
+                                         text: format!("Required tag {} missing", tag) });
+        }
+    }
+    match dictionary {
+        Some(d) => d.validate_bytes(buf),
+        None => Ok(()),
+    }
+}
+
+/// Streams already validated message into the session request payload
+/// or, for application messages, into the application
+fn deliver<S>(buf: &[u8], app: &mut S) -> Option<SessionRequestType>
+    where S: FixApplication
+{
+    let mut request = None;
+    let mut s = Slicer {buf: buf, len: 0};
+    while let Ok(Some((id, v, _))) = get_tag(&mut s) {
+        match id {
+            header::Version | header::Length => {},
+            header::MsgType => {
+                let msg_type = FixMsgType::from(v);
+                request = Option::<SessionRequestType>::from(&msg_type);
+                if request.is_none() {
+                    app.in_stream().fix_message_start(msg_type, true);
+                }
+            },
+            trailer::CheckSum => {
+                if request.is_none() {
+                    app.in_stream().fix_message_done(Ok(()));
+                }
+                break;
+            },
+            _ => match request {
+                Some(ref mut r) => r.tag_value(id, v),
+                None => app.in_stream().tag_value(id, v),
+            },
+        }
+    }
+    request
+}
+
+impl<T, E> FixService for FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
@@ -150,89 +1454,216 @@ impl<'a, T, E> FixService for FixConnection<'a, T, E>
     {
         println!("Connecting {:?} in: {:?} out {:?}",
                  self.conn_type,
-                 self.in_state,
-                 self.out_state);
+                 self.state.in_state(),
+                 self.state.out_state());
 
-        match self.conn_type {
-            ConnectionType::Initiator => {
-                // TODO: Open output stream, send logon
-                self.in_state = FixInState::Disconnected;
-                self.out_state = FixOutState::Logon;
+        if !self.is_session_time() {
+            trace!("Not connecting outside of session time");
+            return;
+        }
 
-                let t : &'a mut T = self.transport.take().unwrap();
+        self.cancel_reconnect();
+        let endpoint = self.reconnect.as_ref().and_then(|p| p.endpoints.get(self.endpoint).cloned());
+        if let Some(ref endpoint) = endpoint {
+            self.transport.set_endpoint(endpoint);
+        }
 
-                t.connect(move |transport| {
-                    self.transport = Some(transport);
-                    l.on_request(SessionRequest::In(SessionRequestType::Logon(None)), self);
-                });
+        let mut connected = false;
+        self.transport.connect(|_| connected = true);
+        if !connected {
+            self.connect_attempts += 1;
+            if let Some(ref policy) = self.reconnect {
+                // failover to the next endpoint
+                self.endpoint = (self.endpoint + 1) % ::std::cmp::max(1, policy.endpoints.len());
             }
-            ConnectionType::Acceptor => {
-                // TODO: Start listening for logon
-                self.in_state = FixInState::Logon;
-                self.out_state = FixOutState::Disconnected;
-
-                self.transport.take().unwrap().connect(|mut transport| {
-                    println!("Connection accepted!");
-                    transport.on_read(|transport| {
-                        self.transport = Some(transport);
-                        self.read_message(l);
-                    });
-                });
+            let retry_in = self.schedule_reconnect();
+            self.events.push(ConnectionEvent::ConnectFailed {
+                endpoint: endpoint,
+                attempt: self.connect_attempts,
+                retry_in: retry_in,
+            });
+            self.emit_events(l);
+        } else {
+            let event = if self.reconnecting {
+                ConnectionEvent::Reconnected { endpoint: endpoint, attempts: self.connect_attempts }
+            } else {
+                ConnectionEvent::Connected { endpoint: endpoint }
+            };
+            self.reconnecting = false;
+            self.connect_attempts = 0;
+            self.events.push(event);
+            self.emit_events(l);
+
+            self.transition(SessionEvent::Connected);
+            if self.reset_policy.on_logon {
+                self.reset_incoming(1);
+                self.reset_outgoing();
+            }
+            if let ConnectionType::Initiator = self.conn_type {
+                // app gets a chance to fill in the logon before it is sent
+                let logon = LogonFields {
+                    heart_bt_int: DEFAULT_HEART_BT_INT,
+                    reset_seq_num_flag: self.reset_policy.on_logon,
+                    ..Default::default()
+                };
+                l.on_request(SessionRequest::Out(SessionRequestType::Logon(logon)), self);
             }
         }
+
         println!("Connected {:?} in: {:?} out {:?}",
                  self.conn_type,
-                 self.in_state,
-                 self.out_state);
+                 self.state.in_state(),
+                 self.state.out_state());
     }
 
     fn request_done(&mut self, r: SessionRequest)
     {
-        self.fix_message_start(FixMsgType::Logon, false);
-        self.fix_message_done(Ok(()));
-        let match_me = (self.out_state, r);
-        match match_me {
-            (FixOutState::Connected, SessionRequest::In(SessionRequestType::Logon(_))) => {
-            //   self.fix_message_start(FixMsgType::Logon, false);
-            //   self.fix_message_done(Ok(()));
+        match r {
+            SessionRequest::In(SessionRequestType::Logon(_)) if self.state.is_logged_on() => {
+                // mid-session sequence reset, numbers start over so nothing to recover
+                self.peer_next_expected = None;
+                if self.reset_reply_due {
+                    self.reset_reply_due = false;
+                    let reply = LogonFields {
+                        heart_bt_int: self.heart_bt_int,
+                        reset_seq_num_flag: true,
+                        ..Default::default()
+                    };
+                    self.send_logon(reply);
+                }
+            },
+            SessionRequest::In(SessionRequestType::Logon(ref logon)) => {
+                // acceptor goes with the initiator's interval, initiator with the confirmed one
+                if logon.heart_bt_int != 0 || self.is_acceptor() {
+                    self.heart_bt_int = logon.heart_bt_int;
+                }
+                if let ConnectionType::Acceptor = self.conn_type {
+                    // confirm logon echoing negotiated parameters
+                    let reply = LogonFields {
+                        heart_bt_int: logon.heart_bt_int,
+                        reset_seq_num_flag: logon.reset_seq_num_flag || self.reset_policy.on_logon,
+                        ..Default::default()
+                    };
+                    self.send_logon(reply);
+                }
+                self.transition(SessionEvent::LogonAccepted);
+                self.start_heartbeat();
+                if let Some(next) = self.peer_next_expected.take() {
+                    // counterparty missed our messages up to the Logon
+                    if next < self.logon_seq {
+                        let end = self.logon_seq - 1;
+                        self.resend(next, end);
+                    }
+                }
+                if self.send_next_expected {
+                    // our Logon asked for the gap already, counterparty fills it unprompted
+                    if let Some(&first) = self.queue.keys().next() {
+                        if first > self.nextInSeq {
+                            self.resend_end = Some(first - 1);
+                            self.transition(SessionEvent::GapDetected);
+                        }
+                    }
+                }
+                self.send_queued();
+                // logon might have come ahead of expected
+                self.check_gap();
+            },
+            SessionRequest::Out(SessionRequestType::Logon(ref logon)) => {
+                self.heart_bt_int = logon.heart_bt_int;
+                if logon.reset_seq_num_flag {
+                    self.reset_outgoing();
+                    self.reset_requested = true;
+                }
+                self.send_logon(logon.clone());
+            },
+            SessionRequest::Out(ref r) => {
+                self.send_request(r);
+                if let SessionRequestType::Logout(_) = *r {
+                    self.logout_sent();
+                }
             },
             _ => {
             }
         }
     }
+
+    fn request_rejected(&mut self, r: SessionRequest, reason: LogoutFields)
+    {
+        match r {
+            SessionRequest::In(SessionRequestType::Logon(_)) => {
+                self.logout_with(reason);
+            },
+            SessionRequest::Out(SessionRequestType::Logon(_)) => {
+                // app is not willing to log on, e.g. has no credentials
+                self.disconnect();
+            },
+            _ => {
+                // request is simply not carried out
+            }
+        }
+    }
 }
 
-impl<'a, T, E> FixSessionControl for FixConnection<'a, T, E>
+impl<T, E> FixSessionControl for FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
-    fn start_session<L>(&mut self, l: &mut L) where L: FixApplication {}
+    fn start_session<L>(&mut self, l: &mut L)
+        where L: FixApplication
+    {
+        if let FixOutState::Disconnected = self.state.out_state() {
+            self.connect(l);
+        }
+    }
 
     fn end_session<L>(&mut self, l: &mut L)
         where L: FixApplication
     {
-        match self.in_state {
-            FixInState::Disconnected => {
+        match self.state.out_state() {
+            FixOutState::Disconnected | FixOutState::Logout => {
                 // nothing need to be done here
             }
             _ => {
-                self.in_state = FixInState::Disconnected;
-                self.out_state = FixOutState::Logout;
-                l.on_request(SessionRequest::In(SessionRequestType::Logout(None)), self);
+                // app may put Text before Logout is sent
+                l.on_request(SessionRequest::Out(SessionRequestType::Logout(Default::default())), self);
             }
         }
     }
 
+    fn reset_session<L>(&mut self, l: &mut L)
+        where L: FixApplication
+    {
+        if !self.state.is_logged_on() {
+            return;
+        }
+        let logon = LogonFields {
+            heart_bt_int: self.heart_bt_int,
+            reset_seq_num_flag: true,
+            ..Default::default()
+        };
+        l.on_request(SessionRequest::Out(SessionRequestType::Logon(logon)), self);
+    }
+
     fn force_expected_incoming_seq(&mut self, seq: u32) {
         self.nextInSeq = seq;
+        self.save_seqs();
     }
 
     fn get_expected_incoming_seq(&mut self) -> u32 {
         self.nextInSeq
     }
+
+    fn force_next_outgoing_seq(&mut self, seq: u32) {
+        self.next_out_seq = seq;
+        self.save_seqs();
+    }
+
+    fn get_next_outgoing_seq(&mut self) -> u32 {
+        self.next_out_seq
+    }
 }
 
-impl<'a, T, E> FixInChannel for FixConnection<'a, T, E>
+impl<T, E> FixInChannel for FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
@@ -243,38 +1674,99 @@ impl<'a, T, E> FixInChannel for FixConnection<'a, T, E>
     }
 }
 
-impl<'a, T, E> FixOutChannel for FixConnection<'a, T, E>
+impl<T, E> FixOutChannel for FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
-    type FMS = FixConnection<'a, T, E>;
+    type FMS = FixConnection<T, E>;
 
     fn get_out_stream(&mut self) -> &mut Self::FMS {
         self
     }
 }
 
-impl<'a, T, E> FixTagHandler for FixConnection<'a, T, E>
+impl<T, E> FixTagHandler for FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
     fn tag_value(&mut self, t: u32, v: &[u8]) {
-        self.fix_writer.tag_value(t, v);
+        match self.out_mode {
+            OutMode::Reject(_) => {},
+            OutMode::Queue(ref mut msg) => msg.fields.push((t, v.to_vec())),
+            _ => self.fix_writer.tag_value(t, v),
+        }
     }
 }
 
-impl<'a, T, E> FixStream for FixConnection<'a, T, E>
+impl<T, E> FixStream for FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
     type MSG_TYPES = ();
     fn fix_message_done(&mut self, res: Result<(), FixStreamException>) {
         println!("Fix message done!");
-        self.fix_writer.fix_message_done(res);
+        let persist = match ::std::mem::replace(&mut self.out_mode, OutMode::Send) {
+            OutMode::Reject(msg_type) => {
+                if res.is_ok() {
+                    self.send_error = Some(format!("Session not logged on, {} rejected", String::from_utf8_lossy(&msg_type)));
+                }
+                return;
+            },
+            OutMode::Queue(msg) => {
+                if res.is_ok() {
+                    self.out_queue.push_back(msg);
+                }
+                return;
+            },
+            OutMode::Persist => true,
+            OutMode::Send => false,
+        };
+        let aborted = res.is_err();
+        if let Err(e) = self.fix_writer.message_done(res) {
+            if !aborted {
+                // i.e. repeating group doesn't add up
+                self.send_error = Some(e);
+            }
+            return;
+        }
+        if self.storing {
+            if let Err(e) = self.store.set(self.next_out_seq, self.fix_writer.last_message()) {
+                // not sent, it could never be re-sent
+                self.fix_writer.discard_last();
+                self.resending = false;
+                self.storing = false;
+                self.send_error = Some(e.clone());
+                self.store_failed(e);
+                return;
+            }
+        }
+        let seq = self.next_out_seq;
+        let saved = if self.resending {
+            true
+        } else {
+            self.next_out_seq += 1;
+            self.save_seqs()
+        };
+        self.resending = false;
+        self.storing = false;
+        if !saved {
+            // stored, counterparty gets it on resend once reconnected
+            self.fix_writer.discard_last();
+            return;
+        }
+        if persist {
+            // counterparty gets it on resend
+            self.fix_writer.discard_last();
+            self.persisted.insert(seq);
+        } else {
+            self.out_idle = 0;
+        }
 
         let len = {
             let msg = self.fix_writer.get_bytes();
-            self.transport.as_mut().unwrap().write(msg)
+            #[cfg(feature = "trace-messages")]
+            println!("Write message buf:\n{}", FixDisplay(msg));
+            self.transport.write(msg)
         };
 
         self.fix_writer.drain_head(len);
@@ -282,14 +1774,48 @@ impl<'a, T, E> FixStream for FixConnection<'a, T, E>
 	fn fix_message_start(&mut self, msg_type: FixMsgType<Self::MSG_TYPES>, is_replayable: bool)
     {
         println!("Fix message start!");
-        self.fix_writer.fix_message_start(msg_type, is_replayable);
+        let session_level = FixMsgType::is_session_level(&msg_type);
+        let policy = if session_level || self.state.is_logged_on() {
+            None
+        } else {
+            Some(*self.outbound_policies.get(msg_type.as_bytes()).unwrap_or(&self.outbound_policy))
+        };
+        match policy {
+            Some(OutboundPolicy::Reject) => {
+                self.out_mode = OutMode::Reject(msg_type.as_bytes().to_vec());
+                return;
+            },
+            Some(OutboundPolicy::Queue) => {
+                self.out_mode = OutMode::Queue(QueuedMessage {
+                    msg_type: msg_type.as_bytes().to_vec(),
+                    is_replayable: is_replayable,
+                    fields: vec![],
+                });
+                return;
+            },
+            _ => {},
+        }
+        // session messages are never re-sent, they are gap filled instead
+        let store = is_replayable && !session_level;
+        self.start_message(msg_type, None);
+        self.storing = store;
+        if let Some(OutboundPolicy::Persist) = policy {
+            self.out_mode = OutMode::Persist;
+        }
     }
 }
 
-impl<'a, T, E> FixErrorChannel for FixConnection<'a, T, E>
+impl<T, E> FixErrorChannel for FixConnection<T, E>
     where T: FixTransport,
           E: FixTimerFactory
 {
-    fn error(&mut self, e: FixStreamException) {}
+    /// Transport failed (i.e. connection reset), the session is
+    /// disconnected and reconnects if configured to
+    fn error(&mut self, e: FixStreamException) {
+        trace!("Transport error: {}", e);
+        if self.state.out_state() != FixOutState::Disconnected {
+            self.disconnect();
+        }
+    }
 }
 
@@ -0,0 +1,494 @@
+/*!
+  Runtime data dictionary loaded from QuickFIX style XML.
+
+  Unlike fix_dict (generated at build time) it can be (re)loaded while running,
+  which is handy for counterparties with frequently changing custom tags.
+ */
+use fix::*;
+use qfxml;
+use qfxml::{FieldType, Member, Spec};
+use util;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct FieldDef {
+    pub tag: u32,
+    pub name: String,
+    pub field_type: FieldType,
+    /// enum value and its description
+    pub values: Vec<(String, String)>,
+}
+
+/// Fields allowed at some level of a message (message body, header, group entry)
+#[derive(Debug, Clone, Default)]
+pub struct FieldSet {
+    pub fields: Vec<u32>,
+    pub required: Vec<u32>,
+    pub groups: HashMap<u32, GroupDef>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GroupDef {
+    pub no_tag: u32,
+    pub delimiter: u32,
+    pub entry: FieldSet,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageDef {
+    pub name: String,
+    pub msg_type: String,
+    pub admin: bool,
+    pub body: FieldSet,
+}
+
+/// Reason the message has been found invalid, maps directly onto session level Reject
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub reason: SessionRejectReason,
+    pub tag: Option<u32>,
+    pub text: String,
+}
+
+impl ValidationError {
+    fn new(reason: SessionRejectReason, tag: u32, text: String) -> ValidationError
+    {
+        ValidationError { reason: reason, tag: Some(tag), text: text }
+    }
+}
+
+pub struct DataDictionary {
+    begin_string: String,
+    fields: HashMap<u32, FieldDef>,
+    names: HashMap<String, u32>,
+    header: FieldSet,
+    trailer: FieldSet,
+    messages: HashMap<Vec<u8>, MessageDef>,
+    // all groups flattened, for FixDictionary
+    groups: HashMap<u32, qfxml::Group>,
+}
+
+impl DataDictionary {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DataDictionary, FixStreamException>
+    {
+        let file = File::open(path.as_ref())
+            .map_err(|e| format!("Can't open {}: {}", path.as_ref().display(), e))?;
+        DataDictionary::from_reader(file)
+    }
+
+    pub fn from_reader<R: Read>(r: R) -> Result<DataDictionary, FixStreamException>
+    {
+        DataDictionary::from_spec(&qfxml::parse(r)?)
+    }
+
+    pub fn from_spec(spec: &Spec) -> Result<DataDictionary, FixStreamException>
+    {
+        let mut fields = HashMap::new();
+        let mut names = HashMap::new();
+        for f in &spec.fields {
+            names.insert(f.name.clone(), f.number);
+            fields.insert(f.number, FieldDef {
+                tag: f.number,
+                name: f.name.clone(),
+                field_type: f.field_type,
+                values: f.values.clone(),
+            });
+        }
+
+        let mut messages = HashMap::new();
+        for m in &spec.messages {
+            messages.insert(m.msg_type.as_bytes().to_vec(), MessageDef {
+                name: m.name.clone(),
+                msg_type: m.msg_type.clone(),
+                admin: m.msg_cat == "admin",
+                body: field_set(spec, &spec.expand(&m.members)?)?,
+            });
+        }
+
+        let mut groups = HashMap::new();
+        for g in spec.groups()? {
+            groups.insert(g.no_tag, g);
+        }
+
+        Ok(DataDictionary {
+            begin_string: spec.begin_string.clone(),
+            fields: fields,
+            names: names,
+            header: field_set(spec, &spec.expand(&spec.header)?)?,
+            trailer: field_set(spec, &spec.expand(&spec.trailer)?)?,
+            messages: messages,
+            groups: groups,
+        })
+    }
+
+    pub fn begin_string(&self) -> &str
+    {
+        &self.begin_string
+    }
+
+    pub fn field(&self, tag: u32) -> Option<&FieldDef>
+    {
+        self.fields.get(&tag)
+    }
+
+    pub fn field_by_name(&self, name: &str) -> Option<&FieldDef>
+    {
+        self.names.get(name).and_then(|tag| self.fields.get(tag))
+    }
+
+    pub fn message(&self, msg_type: &[u8]) -> Option<&MessageDef>
+    {
+        self.messages.get(msg_type)
+    }
+
+    pub fn header(&self) -> &FieldSet
+    {
+        &self.header
+    }
+
+    pub fn trailer(&self) -> &FieldSet
+    {
+        &self.trailer
+    }
+
+    /// Whether tag is required on the top level of the message (header and trailer included)
+    pub fn is_required(&self, msg_type: &[u8], tag: u32) -> bool
+    {
+        self.header.required.contains(&tag) ||
+            self.trailer.required.contains(&tag) ||
+            self.message(msg_type).map_or(false, |m| m.body.required.contains(&tag))
+    }
+
+    /// Top level group of the message
+    pub fn group(&self, msg_type: &[u8], no_tag: u32) -> Option<&GroupDef>
+    {
+        self.header.groups.get(&no_tag)
+            .or_else(|| self.message(msg_type).and_then(|m| m.body.groups.get(&no_tag)))
+    }
+
+    /// Whether value is one of the enumerated values of the field,
+    /// true for fields that are not enumerated
+    pub fn is_valid_value(&self, tag: u32, value: &[u8]) -> bool
+    {
+        match self.field(tag) {
+            Some(f) if !f.values.is_empty() => {
+                let valid = |v: &[u8]| f.values.iter().any(|e| e.0.as_bytes() == v);
+                match f.field_type {
+                    FieldType::MultipleValueString | FieldType::MultipleCharValue => {
+                        value.split(|&c| c == b' ').all(valid)
+                    },
+                    _ => valid(value),
+                }
+            },
+            _ => true,
+        }
+    }
+
+    /// Checks single field: known tag, non empty value, format and enum value
+    pub fn check_field(&self, tag: u32, value: &[u8]) -> Result<(), ValidationError>
+    {
+        let f = match self.field(tag) {
+            Some(f) => f,
+            None => return Err(ValidationError::new(SessionRejectReason::UndefinedTag, tag,
+                                                    format!("Undefined tag {}", tag))),
+        };
+        if value.is_empty() {
+            return Err(ValidationError::new(SessionRejectReason::TagSpecifiedWithoutValue, tag,
+                                            format!("Tag {} specified without a value", tag)));
+        }
+        if !is_valid_format(f.field_type, value) {
+            return Err(ValidationError::new(SessionRejectReason::IncorrectDataFormat, tag,
+                                            format!("Incorrect data format for {}({})", f.name, tag)));
+        }
+        if !self.is_valid_value(tag, value) {
+            return Err(ValidationError::new(SessionRejectReason::ValueIsIncorrect, tag,
+                                            format!("Value is incorrect (out of range) for {}({})", f.name, tag)));
+        }
+        Ok(())
+    }
+
+    /// Validates message fields (everything but BeginString, BodyLength, MsgType and CheckSum)
+    pub fn validate(&self, msg_type: &[u8], fields: &[(u32, &[u8])]) -> Result<(), ValidationError>
+    {
+        let msg = match self.message(msg_type) {
+            Some(msg) => msg,
+            None => return Err(ValidationError::new(SessionRejectReason::InvalidMsgType, 35,
+                                                    format!("Invalid MsgType {}", String::from_utf8_lossy(msg_type)))),
+        };
+
+        let mut seen = vec![];
+        let mut pos = 0;
+        while pos < fields.len() {
+            let (tag, value) = fields[pos];
+            self.check_field(tag, value)?;
+            if seen.contains(&tag) {
+                return Err(ValidationError::new(SessionRejectReason::TagAppearsMoreThanOnce, tag,
+                                                format!("Tag {} appears more than once", tag)));
+            }
+            seen.push(tag);
+            pos += 1;
+
+            let group = self.header.groups.get(&tag).or_else(|| msg.body.groups.get(&tag));
+            if let Some(group) = group {
+                self.validate_group(group, value, fields, &mut pos)?;
+            }
+            else if !self.header.fields.contains(&tag) &&
+                    !self.trailer.fields.contains(&tag) &&
+                    !msg.body.fields.contains(&tag) {
+                return Err(ValidationError::new(SessionRejectReason::TagNotDefinedForMessageType, tag,
+                                                format!("Tag {} not defined for message type {}", tag, msg.msg_type)));
+            }
+        }
+
+        for set in &[&self.header, &msg.body, &self.trailer] {
+            for tag in &set.required {
+                match *tag {
+                    // not part of the tag-value stream
+                    8 | 9 | 35 | 10 => {},
+                    t if !seen.contains(&t) => {
+                        return Err(ValidationError::new(SessionRejectReason::RequiredTagMissing, t,
+                                                        format!("Required tag {} missing", t)));
+                    },
+                    _ => {},
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates first message in the buffer (raw tag-value view)
+    pub fn validate_bytes(&self, buf: &[u8]) -> Result<(), ValidationError>
+    {
+        let mut v = FixValidator::new(self);
+        match util::parse_fix_message(buf, &mut v) {
+            Ok(Some(_)) => v.result().unwrap_or(Ok(())),
+            Ok(None) => Err(ValidationError { reason: SessionRejectReason::Other, tag: None,
+                                              text: String::from("Incomplete message") }),
+            Err(e) => Err(ValidationError { reason: SessionRejectReason::Other, tag: None, text: e }),
+        }
+    }
+
+    fn validate_group(&self, group: &GroupDef, count: &[u8], fields: &[(u32, &[u8])], pos: &mut usize)
+        -> Result<(), ValidationError>
+    {
+        let count = util::parse_u32(count).unwrap_or(0);
+        let mut entries = 0;
+        while *pos < fields.len() && fields[*pos].0 == group.delimiter {
+            entries += 1;
+            let mut seen = vec![];
+            loop {
+                let (tag, value) = fields[*pos];
+                self.check_field(tag, value)?;
+                if seen.contains(&tag) {
+                    return Err(ValidationError::new(SessionRejectReason::TagAppearsMoreThanOnce, tag,
+                                                    format!("Tag {} appears more than once in group {}", tag, group.no_tag)));
+                }
+                seen.push(tag);
+                *pos += 1;
+                if let Some(inner) = group.entry.groups.get(&tag) {
+                    self.validate_group(inner, value, fields, pos)?;
+                }
+                if *pos >= fields.len() ||
+                    fields[*pos].0 == group.delimiter ||
+                    !group.entry.fields.contains(&fields[*pos].0) {
+                    break;
+                }
+            }
+            for tag in &group.entry.required {
+                if !seen.contains(tag) {
+                    return Err(ValidationError::new(SessionRejectReason::RequiredTagMissing, *tag,
+                                                    format!("Required tag {} missing in group {}", tag, group.no_tag)));
+                }
+            }
+        }
+
+        if entries == 0 && count > 0 && *pos < fields.len() && group.entry.fields.contains(&fields[*pos].0) {
+            return Err(ValidationError::new(SessionRejectReason::RepeatingGroupFieldsOutOfOrder, fields[*pos].0,
+                                            format!("Group {} entry has to start with {}", group.no_tag, group.delimiter)));
+        }
+        if entries != count {
+            return Err(ValidationError::new(SessionRejectReason::IncorrectNumInGroupCount, group.no_tag,
+                                            format!("Group {} declared {} entries got {}", group.no_tag, count, entries)));
+        }
+        Ok(())
+    }
+}
+
+fn field_set(spec: &Spec, members: &[Member]) -> Result<FieldSet, FixStreamException>
+{
+    let mut set = FieldSet::default();
+    for m in members {
+        match *m {
+            Member::Field { ref name, required } => {
+                let tag = spec.tag(name)?;
+                set.fields.push(tag);
+                if required {
+                    set.required.push(tag);
+                }
+            },
+            Member::Group { ref name, required, ref members } => {
+                let tag = spec.tag(name)?;
+                set.fields.push(tag);
+                if required {
+                    set.required.push(tag);
+                }
+                let entry = field_set(spec, members)?;
+                let delimiter = match entry.fields.first() {
+                    Some(d) => *d,
+                    None => return Err(format!("Empty group {}", name)),
+                };
+                set.groups.insert(tag, GroupDef { no_tag: tag, delimiter: delimiter, entry: entry });
+            },
+            Member::Component { ref name, .. } => {
+                return Err(format!("Component {} is not expanded", name));
+            },
+        }
+    }
+    Ok(set)
+}
+
+fn is_digits(v: &[u8]) -> bool
+{
+    !v.is_empty() && v.iter().all(|c| c.is_ascii_digit())
+}
+
+fn is_valid_format(t: FieldType, v: &[u8]) -> bool
+{
+    match t {
+        FieldType::Int => {
+            is_digits(if v[0] == b'-' { &v[1..] } else { v })
+        },
+        FieldType::Length | FieldType::SeqNum | FieldType::NumInGroup |
+        FieldType::TagNum | FieldType::DayOfMonth => is_digits(v),
+        FieldType::Float | FieldType::Qty | FieldType::Price | FieldType::PriceOffset |
+        FieldType::Amt | FieldType::Percentage => {
+            let v = if v[0] == b'-' { &v[1..] } else { v };
+            let mut parts = v.splitn(2, |&c| c == b'.');
+            let int = parts.next().unwrap_or(b"");
+            match parts.next() {
+                Some(frac) => (int.is_empty() || is_digits(int)) && (frac.is_empty() || is_digits(frac)) &&
+                    !(int.is_empty() && frac.is_empty()),
+                None => is_digits(int),
+            }
+        },
+        FieldType::Char => v.len() == 1,
+        FieldType::Boolean => v == b"Y" || v == b"N",
+        // YYYYMMDD-HH:MM:SS[.sss[sss[sss[sss]]]]
+        FieldType::UtcTimestamp => {
+            v.len() >= 17 &&
+                is_digits(&v[0..8]) && v[8] == b'-' && is_valid_format(FieldType::UtcTimeOnly, &v[9..])
+        },
+        // HH:MM:SS[.sss[sss[sss[sss]]]], milli- down to picoseconds
+        FieldType::UtcTimeOnly => {
+            let frac = v.len().saturating_sub(9);
+            (v.len() == 8 || (frac > 0 && frac <= 12 && frac % 3 == 0)) &&
+                is_digits(&v[0..2]) && v[2] == b':' && is_digits(&v[3..5]) && v[5] == b':' && is_digits(&v[6..8]) &&
+                (v.len() == 8 || (v[8] == b'.' && is_digits(&v[9..])))
+        },
+        FieldType::UtcDateOnly | FieldType::LocalMktDate => v.len() == 8 && is_digits(v),
+        _ => true,
+    }
+}
+
+/// FixStream sink validating the message against the dictionary.
+/// Feed it with the message, then check result()
+pub struct FixValidator<'d>
+{
+    dictionary: &'d DataDictionary,
+    msg_type: Vec<u8>,
+    fields: Vec<(u32, Vec<u8>)>,
+    result: Option<Result<(), ValidationError>>,
+}
+
+impl<'d> FixValidator<'d>
+{
+    pub fn new(dictionary: &'d DataDictionary) -> FixValidator<'d>
+    {
+        FixValidator {
+            dictionary: dictionary,
+            msg_type: vec![],
+            fields: vec![],
+            result: None,
+        }
+    }
+
+    /// Result of the last complete message, None if there was none
+    pub fn result(&mut self) -> Option<Result<(), ValidationError>>
+    {
+        self.result.take()
+    }
+}
+
+impl<'d> FixTagHandler for FixValidator<'d>
+{
+    fn tag_value(&mut self, t: u32, v: &[u8])
+    {
+        self.fields.push((t, v.to_vec()));
+    }
+}
+
+impl<'d> FixStream for FixValidator<'d>
+{
+    type MSG_TYPES = ();
+
+    fn fix_message_start(&mut self, msg_type: FixMsgType<Self::MSG_TYPES>, is_replayable: bool)
+    {
+        self.msg_type = msg_type.as_bytes().to_vec();
+        self.fields.clear();
+    }
+
+    fn fix_message_done(&mut self, res: Result<(), FixStreamException>)
+    {
+        self.result = Some(match res {
+            Ok(()) => {
+                let fields: Vec<(u32, &[u8])> = self.fields.iter().map(|&(t, ref v)| (t, &v[..])).collect();
+                self.dictionary.validate(&self.msg_type, &fields)
+            },
+            Err(e) => Err(ValidationError { reason: SessionRejectReason::Other, tag: None, text: e }),
+        });
+        self.fields.clear();
+    }
+}
+
+impl FixDictionary for DataDictionary
+{
+    fn group_delimiter(&self, no_tag: u32) -> Option<u32>
+    {
+        self.groups.get(&no_tag).map(|g| g.delimiter)
+    }
+
+    fn group_member(&self, no_tag: u32, tag: u32) -> bool
+    {
+        self.groups.get(&no_tag).map_or(false, |g| g.members.contains(&tag))
+    }
+
+    fn field_name(&self, tag: u32) -> Option<&str>
+    {
+        self.field(tag).map(|f| f.name.as_str())
+    }
+
+    fn value_name(&self, tag: u32, value: &[u8]) -> Option<&str>
+    {
+        self.field(tag)
+            .and_then(|f| f.values.iter().find(|v| v.0.as_bytes() == value))
+            .map(|v| v.1.as_str())
+    }
+
+    fn field_tag(&self, name: &str) -> Option<u32>
+    {
+        self.names.get(name).cloned()
+    }
+
+    fn is_header_field(&self, tag: u32) -> bool
+    {
+        self.header.fields.contains(&tag)
+    }
+
+    fn is_trailer_field(&self, tag: u32) -> bool
+    {
+        self.trailer.fields.contains(&tag)
+    }
+}
@@ -6,15 +6,20 @@ use std::convert::Into;
 use std::fmt::Error;
 use std::fmt::Debug;
 use std::fmt::Formatter;
-use std::time::Duration;
-use futures::{Future, Sink, Stream};
+use std::time::{Duration, SystemTime};
+use fix_tags::{header, body, trailer};
+use message::FieldValue;
+use dictionary::ValidationError;
 
 pub type FixStreamException = String;
 pub type FixParseIdLenSum = (u32, usize, u32);
 
 /// Application level message types
 pub trait FixAppMsgType {
+	/// Maps MsgType(35) value onto application type, None if not known
 	fn lookup(btype: &[u8]) -> Option<Self> where Self: Sized;
+	/// MsgType(35) value of the application type
+	fn as_bytes(&self) -> &[u8];
 }
 
 /// Session level message types
@@ -27,28 +32,166 @@ where T: FixAppMsgType {
 	Heartbeat,
 	TestRequest,
 	ResendRequest,
+	Reject,
+	BusinessMessageReject,
 	Custom(T),
 	Unknown(&'a[u8]),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SessionRequest {
     In(SessionRequestType),
     Out(SessionRequestType),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SessionRequestType {
-    Logon(Option<()>),
-    Logout(Option<()>),
-    SeqReset(Option<()>),
-    Heartbeat(Option<()>),
-    TestRequest(Option<()>),
-    ResendRequest(Option<()>),
+    Logon(LogonFields),
+    Logout(LogoutFields),
+    SeqReset(SeqResetFields),
+    Heartbeat(HeartbeatFields),
+    TestRequest(TestRequestFields),
+    ResendRequest(ResendRequestFields),
+    Reject(RejectFields),
+}
+
+/// Logon(A) body. CompIDs are taken from the header of incoming Logon
+/// so that application can check who is logging on, they are never written
+/// by the payload itself. Tags not known to the session end up in custom.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LogonFields {
+	pub sender_comp_id: Option<String>,
+	pub target_comp_id: Option<String>,
+	pub encrypt_method: u32,
+	pub heart_bt_int: u32,
+	pub reset_seq_num_flag: bool,
+	/// NextExpectedMsgSeqNum(789), FIX.4.4 and later
+	pub next_expected_msg_seq_num: Option<u32>,
+	pub username: Option<String>,
+	pub password: Option<String>,
+	pub new_password: Option<String>,
+	pub custom: Vec<(u32, String)>,
+}
+
+/// Logout(5) body
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LogoutFields {
+	pub text: Option<String>,
+	pub session_status: Option<u32>,
+}
+
+impl LogoutFields {
+	/// Logout rejecting the logon for a given reason
+	pub fn rejected(status: SessionStatus, text: &str) -> LogoutFields
+	{
+		LogoutFields {
+			text: Some(text.to_string()),
+			session_status: Some(status.code()),
+		}
+	}
+}
+
+/// SequenceReset(4) body
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SeqResetFields {
+	pub gap_fill_flag: bool,
+	pub new_seq_no: u32,
+}
+
+/// Heartbeat(0) body, TestReqID is set when answering TestRequest
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HeartbeatFields {
+	pub test_req_id: Option<String>,
+}
+
+/// TestRequest(1) body
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TestRequestFields {
+	pub test_req_id: String,
+}
+
+/// ResendRequest(2) body
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ResendRequestFields {
+	pub begin_seq_no: u32,
+	pub end_seq_no: u32,
+}
+
+/// Reject(3) body
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RejectFields {
+	pub ref_seq_num: u32,
+	pub ref_tag_id: Option<u32>,
+	pub ref_msg_type: Option<String>,
+	pub session_reject_reason: Option<u32>,
+	pub text: Option<String>,
+}
+
+/// SessionRejectReason(373) values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionRejectReason {
+	InvalidTagNumber = 0,
+	RequiredTagMissing = 1,
+	TagNotDefinedForMessageType = 2,
+	UndefinedTag = 3,
+	TagSpecifiedWithoutValue = 4,
+	ValueIsIncorrect = 5,
+	IncorrectDataFormat = 6,
+	DecryptionProblem = 7,
+	SignatureProblem = 8,
+	CompIdProblem = 9,
+	SendingTimeAccuracyProblem = 10,
+	InvalidMsgType = 11,
+	XmlValidationError = 12,
+	TagAppearsMoreThanOnce = 13,
+	TagSpecifiedOutOfRequiredOrder = 14,
+	RepeatingGroupFieldsOutOfOrder = 15,
+	IncorrectNumInGroupCount = 16,
+	NonDataValueIncludesFieldDelimiter = 17,
+	Other = 99,
+}
+
+impl SessionRejectReason {
+	pub fn code(&self) -> u32 {
+		*self as u32
+	}
+}
+
+/// SessionStatus(1409) values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionStatus {
+	SessionActive = 0,
+	SessionPasswordChanged = 1,
+	SessionPasswordDueToExpire = 2,
+	NewSessionPasswordDoesNotComplyWithPolicy = 3,
+	SessionLogoutComplete = 4,
+	InvalidUsernameOrPassword = 5,
+	AccountLocked = 6,
+	LogonsAreNotAllowedAtThisTime = 7,
+	PasswordExpired = 8,
+}
+
+impl SessionStatus {
+	pub fn code(&self) -> u32 {
+		*self as u32
+	}
+}
+
+/// Transport level events, Reconnected tells the connection of the ongoing
+/// session apart from the fresh start
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+	Connected { endpoint: Option<String> },
+	/// connected again after the connection was lost, `attempts` failed in between
+	Reconnected { endpoint: Option<String>, attempts: u32 },
+	/// connect attempt failed, retry_in is None unless reconnect is configured
+	ConnectFailed { endpoint: Option<String>, attempt: u32, retry_in: Option<Duration> },
+	/// transport closed without logout, reconnecting in `retry_in`
+	ConnectionLost { retry_in: Duration },
 }
 
 /// "Outgoing" connection state
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FixOutState {
 	Disconnected,
 	Logon,
@@ -59,7 +202,7 @@ pub enum FixOutState {
 }
 
 /// "Incoming" connection state
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FixInState {
 	Disconnected,
 	Logon,
@@ -78,6 +221,26 @@ pub enum ParseControl {
 	Skip,
 }
 
+/// Message layout knowledge (normally coming from a data dictionary).
+/// Used by writers and readers that need more than the raw tag-value stream.
+pub trait FixDictionary {
+	/// For a group counter tag (NoXXX) returns the tag every group entry has to start with.
+	/// None if the tag is not known to be a group counter.
+	fn group_delimiter(&self, no_tag: u32) -> Option<u32>;
+	/// Whether `tag` is a field of group entries counted by `no_tag`
+	fn group_member(&self, no_tag: u32, tag: u32) -> bool { false }
+	/// Human readable tag name i.e. OrdStatus for 39
+	fn field_name(&self, tag: u32) -> Option<&str> { None }
+	/// Human readable enum value name i.e. FILLED for 39=2
+	fn value_name(&self, tag: u32, value: &[u8]) -> Option<&str> { None }
+	/// Reverse of field_name
+	fn field_tag(&self, name: &str) -> Option<u32> { None }
+	/// Whether the tag belongs to the standard header
+	fn is_header_field(&self, tag: u32) -> bool { ::fix_tags::header::is_header(tag) }
+	/// Whether the tag belongs to the standard trailer
+	fn is_trailer_field(&self, tag: u32) -> bool { ::fix_tags::trailer::is_trailer(tag) }
+}
+
 /// Tag-value processor, required to parse tag-value stream
 pub trait FixTagHandler {
 	fn tag_value(&mut self, t: u32, v: &[u8]);
@@ -118,12 +281,21 @@ pub trait FixErrorChannel {
 
 /// FixApplication is configured with session control object that allows
 /// start/end fix session as well as query and force expected incoming sequnce
+/// and the sequence number of the next outgoing message
 pub trait FixSessionControl
 {
+	/// Connects unless already connected, initiator then sends Logon
 	fn start_session<L>(&mut self, l: &mut L) where L: FixApplication;
+	/// Sends Logout (app may add Text), transport is closed once the
+	/// counterparty replies or the logout timeout expires
 	fn end_session<L>(&mut self, l: &mut L) where L: FixApplication;
+	/// Restarts sequence numbers of both sides from 1 with Logon
+	/// carrying ResetSeqNumFlag(141), session stays logged on
+	fn reset_session<L>(&mut self, l: &mut L) where L: FixApplication;
 	fn force_expected_incoming_seq(&mut self, seq: u32);
 	fn get_expected_incoming_seq(&mut self) -> u32;
+	fn force_next_outgoing_seq(&mut self, seq: u32);
+	fn get_next_outgoing_seq(&mut self) -> u32;
 }
 
 /// Every FixApplication has to implement this trait
@@ -131,6 +303,12 @@ pub trait FixSessionControl
 pub trait FixApplication {
 	type FIX_STREAM: FixStream;
 	/// FixService request to perform certain action.
+	/// In(Logon) - counterparty logs on, app checks the credentials and either
+	/// accepts with request_done or declines with request_rejected.
+	/// Out(Logon) - logon about to be sent, app may put credentials (username,
+	/// password and custom tags) into the payload before calling request_done.
+	/// In(TestRequest), In(ResendRequest) and In(Logout) are answered by the
+	/// session right after the app is notified, request_done is not needed.
 	fn on_request<S>(&mut self, r: SessionRequest, svs: &mut S) 
     where S: FixService, <S as FixOutChannel>::FMS: FixStream;
 	/// FixService notifies app that there are potentially
@@ -140,11 +318,18 @@ pub trait FixApplication {
 	fn on_message_pending<C>(&mut self, in_ch: &mut C) where C: FixInChannel;
 
     fn in_stream(&mut self) -> &mut Self::FIX_STREAM;
+
+	/// Incoming message failed validation and was answered with session
+	/// level Reject, the message itself is not delivered
+	fn on_session_reject(&mut self, reject: &RejectFields, error: &ValidationError) {}
+
+	/// Transport connected, lost or failed to connect, see ConnectionEvent
+	fn on_connection_event(&mut self, event: &ConnectionEvent) {}
 }
 
 /// TimerHandler that allows to cancel previously scheduled timeout
 pub trait FixTimerHandler {
-	fn cancel(self);
+	fn cancel(self: Box<Self>);
 }
 
 /// Facility to provide fix "transport" with ability to set timeouts
@@ -153,18 +338,39 @@ pub trait FixTimerHandler {
 pub trait FixTimerFactory
 {
 	fn set_timeout<F>(&mut self, on_timeout: F, duration: Duration) -> Box<FixTimerHandler>
-	   where F: Fn() -> () + Send;
+	   where F: Fn() -> () + Send + 'static;
+}
+
+/// Source of the current time for session schedules and SendingTime,
+/// injectable so that tests do not depend on the wall clock
+pub trait FixClock
+{
+	fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl FixClock for SystemClock
+{
+	fn now(&self) -> SystemTime {
+		SystemTime::now()
+	}
 }
 
 /// Transport abstraction. FIX is a stream and thus the most tipical transport is 
 /// TCP however any other stream will do, and as such there is a reson to abstract this.
+/// on_success is called once the other side is connected (or accepted).
 pub trait FixTransport {
-	fn connect<F, SNK, SRC>(&mut self) -> F
-        where F: Future<Item=(SNK, SRC), Error=String>, SNK: Sink, SRC: Stream;
+	fn connect<F>(&mut self, on_success: F) where F: FnOnce(&mut Self) -> ();
 	fn view(&self) -> &[u8];
 	fn consume(&mut self, len: usize);
 	fn write(&mut self, buf: &[u8]) -> usize;
+	/// Closes the connection, nothing is read or written afterwards
+	fn disconnect(&mut self);
 	fn on_read<F>(&mut self, on_read: F) where F: FnOnce(&mut Self) -> ();
+	/// Endpoint (host:port) the next connect goes to, set before every
+	/// connect when failover endpoints are configured
+	fn set_endpoint(&mut self, _endpoint: &str) {}
 }
 
 /// Abstraction representing either FixClient or FixServer
@@ -174,6 +380,9 @@ pub trait FixService: FixInChannel + FixOutChannel + FixErrorChannel + FixSessio
 {
 	fn connect<L>(&mut self, l: &mut L) where L: FixApplication;
 	fn request_done(&mut self, r: SessionRequest); 
+	/// Application declines the request. Incoming Logon is answered with
+	/// Logout carrying the reason (Text, SessionStatus) and connection is closed.
+	fn request_rejected(&mut self, r: SessionRequest, reason: LogoutFields);
 }
 
 impl<'b, 'a:'b, T> From<&'a[u8]> for FixMsgType<'b, T>
@@ -181,17 +390,24 @@ where T: FixAppMsgType
 {
 	fn from(btype: &'a[u8]) -> Self 
 	{
-		match btype.len() {
-			1 => {
-				match btype[0] as char {
-					'A' => return FixMsgType::Logon,
-					_ => return FixMsgType::Unknown(&btype),
-				}
-			},
-			_ => return FixMsgType::Unknown(&btype),
+		if btype.len() == 1 {
+			match btype[0] {
+				b'0' => return FixMsgType::Heartbeat,
+				b'1' => return FixMsgType::TestRequest,
+				b'2' => return FixMsgType::ResendRequest,
+				b'3' => return FixMsgType::Reject,
+				b'4' => return FixMsgType::SeqReset,
+				b'5' => return FixMsgType::Logout,
+				b'A' => return FixMsgType::Logon,
+				b'j' => return FixMsgType::BusinessMessageReject,
+				_ => {},
+			}
 		}
 
-		FixMsgType::Unknown(&btype)
+		match T::lookup(btype) {
+			Some(t) => FixMsgType::Custom(t),
+			None => FixMsgType::Unknown(btype),
+		}
 	}
 }
 
@@ -201,14 +417,16 @@ where T: FixAppMsgType
 	pub fn as_bytes(&self) -> &[u8]
 	{
 		match *self {
-		    FixMsgType::Logon => { "A".as_bytes() },
-			FixMsgType::Logout => { "5".as_bytes() }
-			FixMsgType::SeqReset => { "5".as_bytes() }
-			FixMsgType::Heartbeat => { "5".as_bytes() }
-			FixMsgType::TestRequest => { "5".as_bytes() }
-			FixMsgType::ResendRequest => { "5".as_bytes() }
-			FixMsgType::Unknown(t) => { t },
-			FixMsgType::Custom(ref t) => { "Custom".as_bytes() },
+			FixMsgType::Heartbeat => b"0",
+			FixMsgType::TestRequest => b"1",
+			FixMsgType::ResendRequest => b"2",
+			FixMsgType::Reject => b"3",
+			FixMsgType::SeqReset => b"4",
+			FixMsgType::Logout => b"5",
+			FixMsgType::Logon => b"A",
+			FixMsgType::BusinessMessageReject => b"j",
+			FixMsgType::Unknown(t) => t,
+			FixMsgType::Custom(ref t) => t.as_bytes(),
 		}
 	}
 
@@ -219,7 +437,8 @@ where T: FixAppMsgType
 			FixMsgType::SeqReset | 
 			FixMsgType::Heartbeat | 
 			FixMsgType::TestRequest | 
-			FixMsgType::ResendRequest => true,
+			FixMsgType::ResendRequest |
+			FixMsgType::Reject => true,
 			_ => false,
 		}
 	}
@@ -231,14 +450,157 @@ where T: FixAppMsgType
     fn from(msg_type: &FixMsgType<'b, T>) -> Self
     {
 		match *msg_type {
-		    FixMsgType::Logon => { Some(SessionRequestType::Logon(None)) },
-			FixMsgType::Logout => { Some(SessionRequestType::Logout(None)) }
-			FixMsgType::SeqReset => { Some(SessionRequestType::SeqReset(None)) }
-			FixMsgType::Heartbeat => { Some(SessionRequestType::Heartbeat(None)) }
-			FixMsgType::TestRequest => { Some(SessionRequestType::TestRequest(None)) }
-			FixMsgType::ResendRequest => { Some(SessionRequestType::ResendRequest(None)) }
+		    FixMsgType::Logon => { Some(SessionRequestType::Logon(Default::default())) },
+			FixMsgType::Logout => { Some(SessionRequestType::Logout(Default::default())) }
+			FixMsgType::SeqReset => { Some(SessionRequestType::SeqReset(Default::default())) }
+			FixMsgType::Heartbeat => { Some(SessionRequestType::Heartbeat(Default::default())) }
+			FixMsgType::TestRequest => { Some(SessionRequestType::TestRequest(Default::default())) }
+			FixMsgType::ResendRequest => { Some(SessionRequestType::ResendRequest(Default::default())) }
+			FixMsgType::Reject => { Some(SessionRequestType::Reject(Default::default())) }
+			FixMsgType::BusinessMessageReject => { None },
 			FixMsgType::Unknown(t) => { None },
 			FixMsgType::Custom(ref t) => { None },
 		}
     }
 }
+
+impl SessionRequestType {
+	/// MsgType(35) of the session message carrying the request
+	pub fn msg_type<T: FixAppMsgType>(&self) -> FixMsgType<'static, T>
+	{
+		match *self {
+			SessionRequestType::Logon(_) => FixMsgType::Logon,
+			SessionRequestType::Logout(_) => FixMsgType::Logout,
+			SessionRequestType::SeqReset(_) => FixMsgType::SeqReset,
+			SessionRequestType::Heartbeat(_) => FixMsgType::Heartbeat,
+			SessionRequestType::TestRequest(_) => FixMsgType::TestRequest,
+			SessionRequestType::ResendRequest(_) => FixMsgType::ResendRequest,
+			SessionRequestType::Reject(_) => FixMsgType::Reject,
+		}
+	}
+
+	/// Writes body fields of the request
+	pub fn write<H: FixTagHandler + ?Sized>(&self, h: &mut H)
+	{
+		match *self {
+			SessionRequestType::Logon(ref f) => {
+				f.encrypt_method.write_fix(body::EncryptMethod, h);
+				f.heart_bt_int.write_fix(body::HeartBtInt, h);
+				if f.reset_seq_num_flag {
+					true.write_fix(body::ResetSeqNumFlag, h);
+				}
+				write_opt(&f.next_expected_msg_seq_num, body::NextExpectedMsgSeqNum, h);
+				write_opt(&f.username, body::Username, h);
+				write_opt(&f.password, body::Password, h);
+				write_opt(&f.new_password, body::NewPassword, h);
+				for &(tag, ref v) in &f.custom {
+					v.write_fix(tag, h);
+				}
+			},
+			SessionRequestType::Logout(ref f) => {
+				write_opt(&f.text, body::Text, h);
+				write_opt(&f.session_status, body::SessionStatus, h);
+			},
+			SessionRequestType::SeqReset(ref f) => {
+				if f.gap_fill_flag {
+					true.write_fix(body::GapFillFlag, h);
+				}
+				f.new_seq_no.write_fix(body::NewSeqNo, h);
+			},
+			SessionRequestType::Heartbeat(ref f) => {
+				write_opt(&f.test_req_id, body::TestReqID, h);
+			},
+			SessionRequestType::TestRequest(ref f) => {
+				f.test_req_id.write_fix(body::TestReqID, h);
+			},
+			SessionRequestType::ResendRequest(ref f) => {
+				f.begin_seq_no.write_fix(body::BeginSeqNo, h);
+				f.end_seq_no.write_fix(body::EndSeqNo, h);
+			},
+			SessionRequestType::Reject(ref f) => {
+				f.ref_seq_num.write_fix(body::RefSeqNum, h);
+				write_opt(&f.ref_tag_id, body::RefTagID, h);
+				write_opt(&f.ref_msg_type, body::RefMsgType, h);
+				write_opt(&f.session_reject_reason, body::SessionRejectReason, h);
+				write_opt(&f.text, body::Text, h);
+			},
+		}
+	}
+}
+
+/// Fills request payload from the incoming session message,
+/// tags that do not belong to the payload are ignored
+impl FixTagHandler for SessionRequestType {
+	fn tag_value(&mut self, t: u32, v: &[u8])
+	{
+		match *self {
+			SessionRequestType::Logon(ref mut f) => match t {
+				header::SenderCompID => set_opt(&mut f.sender_comp_id, v),
+				header::TargetCompID => set_opt(&mut f.target_comp_id, v),
+				body::EncryptMethod => set(&mut f.encrypt_method, v),
+				body::HeartBtInt => set(&mut f.heart_bt_int, v),
+				body::ResetSeqNumFlag => set(&mut f.reset_seq_num_flag, v),
+				body::NextExpectedMsgSeqNum => set_opt(&mut f.next_expected_msg_seq_num, v),
+				body::Username => set_opt(&mut f.username, v),
+				body::Password => set_opt(&mut f.password, v),
+				body::NewPassword => set_opt(&mut f.new_password, v),
+				_ if !header::is_header(t) && !trailer::is_trailer(t) => {
+					f.custom.push((t, String::from_utf8_lossy(v).into_owned()));
+				},
+				_ => {},
+			},
+			SessionRequestType::Logout(ref mut f) => match t {
+				body::Text => set_opt(&mut f.text, v),
+				body::SessionStatus => set_opt(&mut f.session_status, v),
+				_ => {},
+			},
+			SessionRequestType::SeqReset(ref mut f) => match t {
+				body::GapFillFlag => set(&mut f.gap_fill_flag, v),
+				body::NewSeqNo => set(&mut f.new_seq_no, v),
+				_ => {},
+			},
+			SessionRequestType::Heartbeat(ref mut f) => match t {
+				body::TestReqID => set_opt(&mut f.test_req_id, v),
+				_ => {},
+			},
+			SessionRequestType::TestRequest(ref mut f) => match t {
+				body::TestReqID => set(&mut f.test_req_id, v),
+				_ => {},
+			},
+			SessionRequestType::ResendRequest(ref mut f) => match t {
+				body::BeginSeqNo => set(&mut f.begin_seq_no, v),
+				body::EndSeqNo => set(&mut f.end_seq_no, v),
+				_ => {},
+			},
+			SessionRequestType::Reject(ref mut f) => match t {
+				body::RefSeqNum => set(&mut f.ref_seq_num, v),
+				body::RefTagID => set_opt(&mut f.ref_tag_id, v),
+				body::RefMsgType => set_opt(&mut f.ref_msg_type, v),
+				body::SessionRejectReason => set_opt(&mut f.session_reject_reason, v),
+				body::Text => set_opt(&mut f.text, v),
+				_ => {},
+			},
+		}
+	}
+}
+
+fn set<V: FieldValue>(to: &mut V, v: &[u8])
+{
+	if let Some(val) = V::from_fix(v) {
+		*to = val;
+	}
+}
+
+fn set_opt<V: FieldValue>(to: &mut Option<V>, v: &[u8])
+{
+	if let Some(val) = V::from_fix(v) {
+		*to = Some(val);
+	}
+}
+
+fn write_opt<V: FieldValue, H: FixTagHandler + ?Sized>(val: &Option<V>, tag: u32, h: &mut H)
+{
+	if let Some(ref val) = *val {
+		val.write_fix(tag, h);
+	}
+}
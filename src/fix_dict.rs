@@ -0,0 +1,12 @@
+/*!
+  Tags, field enums, application message types, field metadata and typed
+  messages (see message module) generated by build.rs from the data dictionary
+  (FIXR_DICTIONARY, spec/FIX42.xml by default).
+
+  match msg_type {
+      FixMsgType::Custom(fix_dict::MsgType::NewOrderSingle) => ...
+  }
+ */
+#![allow(non_upper_case_globals)]
+
+include!(concat!(env!("OUT_DIR"), "/fix_dictionary.rs"));
@@ -3,14 +3,195 @@ pub mod header
     pub const Version: u32 = 8;
     pub const Length: u32 = 9;
     pub const MsgType: u32 = 35;
+    pub const MsgSeqNum: u32 = 34;
+    pub const PossDupFlag: u32 = 43;
+    pub const SenderCompID: u32 = 49;
+    pub const SendingTime: u32 = 52;
+    pub const TargetCompID: u32 = 56;
+    pub const PossResend: u32 = 97;
+    pub const OrigSendingTime: u32 = 122;
+
+    /// Standard header tags
+    pub const ALL: &'static [u32] = &[8, 9, 35, 49, 56, 115, 128, 90, 91, 34, 50, 142, 57, 143,
+        116, 144, 129, 145, 43, 97, 52, 122, 212, 213, 347, 369, 627, 628, 629, 630, 1128, 1129];
+
+    pub fn is_header(tag: u32) -> bool
+    {
+        ALL.contains(&tag)
+    }
 }
 
 pub mod body 
 {
+    pub const BeginSeqNo: u32 = 7;
+    pub const EndSeqNo: u32 = 16;
+    pub const NewSeqNo: u32 = 36;
+    pub const RefSeqNum: u32 = 45;
     pub const Text: u32 = 58;
+    pub const EncryptMethod: u32 = 98;
+    pub const HeartBtInt: u32 = 108;
+    pub const TestReqID: u32 = 112;
+    pub const GapFillFlag: u32 = 123;
+    pub const ResetSeqNumFlag: u32 = 141;
+    pub const RefTagID: u32 = 371;
+    pub const RefMsgType: u32 = 372;
+    pub const SessionRejectReason: u32 = 373;
+    pub const Username: u32 = 553;
+    pub const Password: u32 = 554;
+    pub const NextExpectedMsgSeqNum: u32 = 789;
+    pub const NewPassword: u32 = 925;
+    pub const SessionStatus: u32 = 1409;
 }
 
 pub mod trailer
 {
     pub const CheckSum: u32 = 10;
+
+    /// Standard trailer tags
+    pub const ALL: &'static [u32] = &[93, 89, 10];
+
+    pub fn is_trailer(tag: u32) -> bool
+    {
+        ALL.contains(&tag)
+    }
+}
+
+use fix::FixDictionary;
+
+/// Names of the most common tags, used when no data dictionary is loaded
+const FIELD_NAMES: &'static [(u32, &'static str)] = &[
+    (1, "Account"),
+    (6, "AvgPx"),
+    (7, "BeginSeqNo"),
+    (8, "BeginString"),
+    (9, "BodyLength"),
+    (10, "CheckSum"),
+    (11, "ClOrdID"),
+    (14, "CumQty"),
+    (16, "EndSeqNo"),
+    (17, "ExecID"),
+    (20, "ExecTransType"),
+    (31, "LastPx"),
+    (32, "LastShares"),
+    (34, "MsgSeqNum"),
+    (35, "MsgType"),
+    (36, "NewSeqNo"),
+    (37, "OrderID"),
+    (38, "OrderQty"),
+    (39, "OrdStatus"),
+    (40, "OrdType"),
+    (41, "OrigClOrdID"),
+    (43, "PossDupFlag"),
+    (44, "Price"),
+    (45, "RefSeqNum"),
+    (49, "SenderCompID"),
+    (52, "SendingTime"),
+    (54, "Side"),
+    (55, "Symbol"),
+    (56, "TargetCompID"),
+    (58, "Text"),
+    (59, "TimeInForce"),
+    (60, "TransactTime"),
+    (97, "PossResend"),
+    (98, "EncryptMethod"),
+    (108, "HeartBtInt"),
+    (112, "TestReqID"),
+    (122, "OrigSendingTime"),
+    (123, "GapFillFlag"),
+    (141, "ResetSeqNumFlag"),
+    (150, "ExecType"),
+    (151, "LeavesQty"),
+    (371, "RefTagID"),
+    (372, "RefMsgType"),
+    (373, "SessionRejectReason"),
+    (553, "Username"),
+    (554, "Password"),
+    (789, "NextExpectedMsgSeqNum"),
+    (925, "NewPassword"),
+    (1409, "SessionStatus"),
+];
+
+/// Names of enum values of the most common tags
+const VALUE_NAMES: &'static [(u32, &'static str, &'static str)] = &[
+    (35, "0", "HEARTBEAT"),
+    (35, "1", "TEST_REQUEST"),
+    (35, "2", "RESEND_REQUEST"),
+    (35, "3", "REJECT"),
+    (35, "4", "SEQUENCE_RESET"),
+    (35, "5", "LOGOUT"),
+    (35, "8", "EXECUTION_REPORT"),
+    (35, "9", "ORDER_CANCEL_REJECT"),
+    (35, "A", "LOGON"),
+    (35, "D", "NEW_ORDER_SINGLE"),
+    (35, "F", "ORDER_CANCEL_REQUEST"),
+    (35, "G", "ORDER_CANCEL_REPLACE_REQUEST"),
+    (35, "j", "BUSINESS_MESSAGE_REJECT"),
+    (39, "0", "NEW"),
+    (39, "1", "PARTIALLY_FILLED"),
+    (39, "2", "FILLED"),
+    (39, "3", "DONE_FOR_DAY"),
+    (39, "4", "CANCELED"),
+    (39, "5", "REPLACED"),
+    (39, "6", "PENDING_CANCEL"),
+    (39, "8", "REJECTED"),
+    (39, "A", "PENDING_NEW"),
+    (39, "E", "PENDING_REPLACE"),
+    (40, "1", "MARKET"),
+    (40, "2", "LIMIT"),
+    (40, "3", "STOP"),
+    (40, "4", "STOP_LIMIT"),
+    (43, "Y", "YES"),
+    (43, "N", "NO"),
+    (54, "1", "BUY"),
+    (54, "2", "SELL"),
+    (54, "5", "SELL_SHORT"),
+    (59, "0", "DAY"),
+    (59, "1", "GOOD_TILL_CANCEL"),
+    (59, "3", "IMMEDIATE_OR_CANCEL"),
+    (59, "4", "FILL_OR_KILL"),
+    (123, "Y", "YES"),
+    (123, "N", "NO"),
+    (141, "Y", "YES"),
+    (141, "N", "NO"),
+    (150, "0", "NEW"),
+    (150, "1", "PARTIAL_FILL"),
+    (150, "2", "FILL"),
+    (150, "4", "CANCELED"),
+    (150, "5", "REPLACED"),
+    (150, "8", "REJECTED"),
+    (150, "F", "TRADE"),
+    (1409, "0", "SESSION_ACTIVE"),
+    (1409, "1", "SESSION_PASSWORD_CHANGED"),
+    (1409, "2", "SESSION_PASSWORD_DUE_TO_EXPIRE"),
+    (1409, "3", "NEW_SESSION_PASSWORD_DOES_NOT_COMPLY_WITH_POLICY"),
+    (1409, "4", "SESSION_LOGOUT_COMPLETE"),
+    (1409, "5", "INVALID_USERNAME_OR_PASSWORD"),
+    (1409, "6", "ACCOUNT_LOCKED"),
+    (1409, "7", "LOGONS_ARE_NOT_ALLOWED_AT_THIS_TIME"),
+    (1409, "8", "PASSWORD_EXPIRED"),
+];
+
+/// Dictionary built from the tags above, knows nothing about groups
+pub struct BuiltinTags;
+
+impl FixDictionary for BuiltinTags
+{
+    fn group_delimiter(&self, no_tag: u32) -> Option<u32> { None }
+
+    fn field_name(&self, tag: u32) -> Option<&str>
+    {
+        FIELD_NAMES.iter().find(|&&(t, _)| t == tag).map(|&(_, name)| name)
+    }
+
+    fn value_name(&self, tag: u32, value: &[u8]) -> Option<&str>
+    {
+        VALUE_NAMES.iter()
+            .find(|&&(t, v, _)| t == tag && v.as_bytes() == value)
+            .map(|&(_, _, name)| name)
+    }
+
+    fn field_tag(&self, name: &str) -> Option<u32>
+    {
+        FIELD_NAMES.iter().find(|&&(_, n)| n == name).map(|&(tag, _)| tag)
+    }
 }
@@ -0,0 +1,157 @@
+/*!
+  Human readable rendering of FIX messages, one field per line:
+
+  MsgType(35)=8 EXECUTION_REPORT
+  OrdStatus(39)=2 FILLED
+ */
+use fix::*;
+use fix_tags::BuiltinTags;
+use util::{Slicer, get_tag};
+use std::fmt;
+use std::fmt::Write;
+
+/// FixStream sink that renders everything it's fed with.
+/// Can be passed to util::parse_fix_message or used as an out stream.
+pub struct FixFormatter<'d>
+{
+    dictionary: &'d FixDictionary,
+    // open groups (NoXXX tag, delimiter tag)
+    groups: Vec<(u32, Option<u32>)>,
+    out: String,
+}
+
+impl<'d> FixFormatter<'d>
+{
+    pub fn new(dictionary: &'d FixDictionary) -> FixFormatter<'d>
+    {
+        FixFormatter {
+            dictionary: dictionary,
+            groups: vec![],
+            out: String::new(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str
+    {
+        &self.out
+    }
+
+    pub fn into_string(self) -> String
+    {
+        self.out
+    }
+
+    fn field(&mut self, tag: u32, value: &[u8])
+    {
+        // leave groups the tag does not belong to
+        while let Some(&(no_tag, delimiter)) = self.groups.last() {
+            if Some(tag) == delimiter || self.dictionary.group_member(no_tag, tag) {
+                break;
+            }
+            self.groups.pop();
+        }
+
+        let depth = self.groups.len();
+        let dict = self.dictionary;
+        write_field(&mut self.out, dict, depth, tag, value);
+
+        if let Some(delimiter) = dict.group_delimiter(tag) {
+            self.groups.push((tag, Some(delimiter)));
+        }
+    }
+}
+
+fn write_field(out: &mut String, dict: &FixDictionary, depth: usize, tag: u32, value: &[u8])
+{
+    for _ in 0..depth {
+        out.push_str("  ");
+    }
+    let value_str = String::from_utf8_lossy(value);
+    let _ = match dict.field_name(tag) {
+        Some(name) => write!(out, "{}({})={}", name, tag, value_str),
+        None => write!(out, "{}={}", tag, value_str),
+    };
+    if let Some(name) = dict.value_name(tag, value) {
+        out.push(' ');
+        out.push_str(name);
+    }
+    out.push('\n');
+}
+
+impl<'d> FixTagHandler for FixFormatter<'d>
+{
+    fn tag_value(&mut self, t: u32, v: &[u8])
+    {
+        self.field(t, v);
+    }
+}
+
+impl<'d> FixStream for FixFormatter<'d>
+{
+    type MSG_TYPES = ();
+
+    fn fix_message_start(&mut self, msg_type: FixMsgType<Self::MSG_TYPES>, is_replayable: bool)
+    {
+        self.groups.clear();
+        self.field(35, msg_type.as_bytes());
+    }
+
+    fn fix_message_done(&mut self, res: Result<(), FixStreamException>)
+    {
+        if let Err(e) = res {
+            let _ = writeln!(self.out, "ERROR: {}", e);
+        }
+        self.groups.clear();
+    }
+}
+
+/// Renders raw tag-value bytes, including header and trailer.
+/// Incomplete trailing field is rendered as is.
+pub fn format_bytes(buf: &[u8], dictionary: &FixDictionary) -> String
+{
+    let mut f = FixFormatter::new(dictionary);
+    let mut s = Slicer {buf: buf, len: 0};
+    loop {
+        match get_tag(&mut s) {
+            Ok(Some((id, v, _))) => {
+                f.field(id, v);
+                if id == 10 {
+                    // message boundary
+                    f.groups.clear();
+                    f.out.push('\n');
+                }
+            },
+            Ok(None) => {
+                if s.len < buf.len() {
+                    let _ = writeln!(f.out, "<incomplete> {}", String::from_utf8_lossy(s.buf()));
+                }
+                break;
+            },
+            Err(e) => {
+                let _ = writeln!(f.out, "ERROR: {} at {}", e, String::from_utf8_lossy(s.buf()));
+                break;
+            },
+        }
+    }
+    f.into_string()
+}
+
+/// Wrapper to render raw FIX bytes using built-in tag names, handy in Debug impls and logs:
+/// println!("{:?}", FixDisplay(buf));
+pub struct FixDisplay<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for FixDisplay<'a>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(&format_bytes(self.0, &BuiltinTags))
+    }
+}
+
+impl<'a> fmt::Debug for FixDisplay<'a>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        fmt::Display::fmt(self, f)
+    }
+}
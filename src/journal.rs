@@ -0,0 +1,367 @@
+/*!
+  Append-only journal in a memory mapped file, FixMessageStore for sessions
+  where FileStore is too slow.
+
+  Journal starts with MAGIC followed by records (integers little endian):
+
+  kind (1) | seq (4) | len (4) | payload (len) | crc32 (4)
+
+  CRC covers everything in front of it. Record with bad CRC or running past
+  the end of the file is a torn write: it and whatever follows is discarded
+  on open, so the store comes back with the last sequence numbers that made
+  it to the disk completely. Unused tail of the file is kept zero filled,
+  kind 0 marks the end of the journal.
+ */
+use fix::FixStreamException;
+use store::FixMessageStore;
+use memmap::MmapMut;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &'static [u8] = b"FIXRJNL1";
+
+/// kind, seq, len and crc
+const RECORD_OVERHEAD: usize = 13;
+
+/// File grows by this much once full
+pub const DEFAULT_CHUNK: usize = 1 << 20;
+
+// record kinds
+const MESSAGE: u8 = 1;
+// payload: next sender seq, next target seq
+const SEQNUMS: u8 = 2;
+// payload: creation time seconds, nanoseconds
+const SESSION: u8 = 3;
+
+/// When written records are forced to the disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// msync after every record, nothing written is lost
+    PerMessage,
+    /// msync once per that many records, crash may lose the last batch
+    Batched(usize),
+    /// left to the OS, survives crash of the process but not of the machine
+    Os,
+}
+
+pub struct JournalStore {
+    path: PathBuf,
+    file: File,
+    map: MmapMut,
+    durability: Durability,
+    chunk: usize,
+    // end of the last complete record
+    end: usize,
+    // everything in front of it is on the disk
+    synced: usize,
+    unsynced: usize,
+    // seq -> (payload offset, len)
+    index: BTreeMap<u32, (usize, usize)>,
+    next_sender: u32,
+    next_target: u32,
+    created: SystemTime,
+    crc_table: [u32; 256],
+}
+
+impl JournalStore {
+    pub fn open<P: AsRef<Path>>(path: P, durability: Durability) -> Result<JournalStore, FixStreamException>
+    {
+        JournalStore::open_with_chunk(path, durability, DEFAULT_CHUNK)
+    }
+
+    /// Opens or creates the journal, `chunk` is the step the file grows by
+    pub fn open_with_chunk<P: AsRef<Path>>(path: P, durability: Durability, chunk: usize)
+        -> Result<JournalStore, FixStreamException>
+    {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path)
+            .map_err(|e| io_error(&path, e))?;
+        let len = file.metadata().map_err(|e| io_error(&path, e))?.len();
+        let chunk = ::std::cmp::max(chunk, MAGIC.len() + RECORD_OVERHEAD + 12);
+        if len < chunk as u64 {
+            file.set_len(chunk as u64).map_err(|e| io_error(&path, e))?;
+        }
+        let map = unsafe { MmapMut::map_mut(&file) }.map_err(|e| io_error(&path, e))?;
+
+        let mut journal = JournalStore {
+            path: path,
+            file: file,
+            map: map,
+            durability: durability,
+            chunk: chunk,
+            end: 0,
+            synced: 0,
+            unsynced: 0,
+            index: BTreeMap::new(),
+            next_sender: 1,
+            next_target: 1,
+            created: SystemTime::now(),
+            crc_table: crc_table(),
+        };
+        journal.recover()?;
+        Ok(journal)
+    }
+
+    /// Length of the valid part of the journal
+    pub fn len(&self) -> usize
+    {
+        self.end
+    }
+
+    /// Forces everything written so far to the disk
+    pub fn sync(&mut self) -> Result<(), FixStreamException>
+    {
+        if self.end > self.synced {
+            let (from, len) = (self.synced, self.end - self.synced);
+            self.map.flush_range(from, len).map_err(|e| io_error(&self.path, e))?;
+        }
+        self.synced = self.end;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Replays the journal up to the first incomplete record
+    fn recover(&mut self) -> Result<(), FixStreamException>
+    {
+        if &self.map[..MAGIC.len()] != MAGIC {
+            // new journal or one that never got initialized completely
+            return self.init();
+        }
+
+        let mut pos = MAGIC.len();
+        while let Some((kind, seq, payload, len)) = self.record_at(pos) {
+            match kind {
+                MESSAGE => {
+                    self.index.insert(seq, (payload, len));
+                },
+                SEQNUMS if len == 8 => {
+                    self.next_sender = read_u32(&self.map, payload);
+                    self.next_target = read_u32(&self.map, payload + 4);
+                },
+                SESSION if len == 12 => {
+                    let secs = read_u32(&self.map, payload) as u64 | (read_u32(&self.map, payload + 4) as u64) << 32;
+                    self.created = UNIX_EPOCH + Duration::new(secs, read_u32(&self.map, payload + 8));
+                },
+                _ => break,
+            }
+            pos = payload + len + 4;
+        }
+        self.end = pos;
+        // message is written before the numbers, crash in between must not reuse its number
+        if let Some((&last, _)) = self.index.iter().next_back() {
+            if last >= self.next_sender {
+                self.next_sender = last + 1;
+            }
+        }
+
+        // torn tail must not come back once new records are written over it
+        if self.map[pos..].iter().any(|&b| b != 0) {
+            for b in &mut self.map[pos..] {
+                *b = 0;
+            }
+            let len = self.map.len() - pos;
+            self.map.flush_range(pos, len).map_err(|e| io_error(&self.path, e))?;
+        }
+        self.synced = self.end;
+        Ok(())
+    }
+
+    /// Starts the journal over with a fresh session
+    fn init(&mut self) -> Result<(), FixStreamException>
+    {
+        let durable = self.durability != Durability::Os;
+        // msync does not order pages, MAGIC goes first so that a crash
+        // can not leave it in front of the old records
+        for b in &mut self.map[..MAGIC.len()] {
+            *b = 0;
+        }
+        if durable {
+            self.map.flush_range(0, MAGIC.len()).map_err(|e| io_error(&self.path, e))?;
+        }
+        for b in &mut self.map[MAGIC.len()..] {
+            *b = 0;
+        }
+        if durable {
+            // records of the old session must not come back either
+            self.map.flush().map_err(|e| io_error(&self.path, e))?;
+        }
+        self.map[..MAGIC.len()].copy_from_slice(MAGIC);
+        self.end = MAGIC.len();
+        self.synced = 0;
+        self.index.clear();
+        self.next_sender = 1;
+        self.next_target = 1;
+        self.created = SystemTime::now();
+
+        let since = self.created.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let mut session = vec![];
+        write_u32(&mut session, since.as_secs() as u32);
+        write_u32(&mut session, (since.as_secs() >> 32) as u32);
+        write_u32(&mut session, since.subsec_nanos());
+        self.append(SESSION, 0, &session)?;
+        self.sync()
+    }
+
+    /// Complete record at `pos`: (kind, seq, payload offset, payload len)
+    fn record_at(&self, pos: usize) -> Option<(u8, u32, usize, usize)>
+    {
+        if pos + RECORD_OVERHEAD > self.map.len() || self.map[pos] == 0 {
+            return None;
+        }
+        let seq = read_u32(&self.map, pos + 1);
+        let len = read_u32(&self.map, pos + 5) as usize;
+        let payload = pos + 9;
+        if len > self.map.len() - payload - 4 {
+            return None;
+        }
+        let crc_pos = payload + len;
+        if crc32(&self.crc_table, &self.map[pos..crc_pos]) != read_u32(&self.map, crc_pos) {
+            return None;
+        }
+        Some((self.map[pos], seq, payload, len))
+    }
+
+    /// Writes record at the end, returns offset of its payload
+    fn append(&mut self, kind: u8, seq: u32, payload: &[u8]) -> Result<usize, FixStreamException>
+    {
+        let need = RECORD_OVERHEAD + payload.len();
+        if self.end + need > self.map.len() {
+            self.grow(need)?;
+        }
+
+        let mut record = Vec::with_capacity(need);
+        record.push(kind);
+        write_u32(&mut record, seq);
+        write_u32(&mut record, payload.len() as u32);
+        record.extend_from_slice(payload);
+        let crc = crc32(&self.crc_table, &record);
+        write_u32(&mut record, crc);
+
+        let start = self.end;
+        self.map[start..start + need].copy_from_slice(&record);
+        self.end += need;
+        self.unsynced += 1;
+
+        match self.durability {
+            Durability::PerMessage => self.sync()?,
+            Durability::Batched(n) if self.unsynced >= n => self.sync()?,
+            _ => {},
+        }
+        Ok(start + 9)
+    }
+
+    fn grow(&mut self, need: usize) -> Result<(), FixStreamException>
+    {
+        if self.durability != Durability::Os {
+            self.sync()?;
+        }
+        let len = self.map.len() + ::std::cmp::max(self.chunk, need);
+        self.file.set_len(len as u64).map_err(|e| io_error(&self.path, e))?;
+        if self.durability != Durability::Os {
+            self.file.sync_all().map_err(|e| io_error(&self.path, e))?;
+        }
+        self.map = unsafe { MmapMut::map_mut(&self.file) }.map_err(|e| io_error(&self.path, e))?;
+        Ok(())
+    }
+
+    fn save_seqnums(&mut self) -> Result<(), FixStreamException>
+    {
+        let mut seqnums = vec![];
+        write_u32(&mut seqnums, self.next_sender);
+        write_u32(&mut seqnums, self.next_target);
+        self.append(SEQNUMS, 0, &seqnums).map(|_| ())
+    }
+}
+
+impl FixMessageStore for JournalStore {
+    fn next_sender_seq(&self) -> u32
+    {
+        self.next_sender
+    }
+
+    fn set_next_sender_seq(&mut self, seq: u32) -> Result<(), FixStreamException>
+    {
+        self.next_sender = seq;
+        self.save_seqnums()
+    }
+
+    fn next_target_seq(&self) -> u32
+    {
+        self.next_target
+    }
+
+    fn set_next_target_seq(&mut self, seq: u32) -> Result<(), FixStreamException>
+    {
+        self.next_target = seq;
+        self.save_seqnums()
+    }
+
+    fn creation_time(&self) -> SystemTime
+    {
+        self.created
+    }
+
+    fn set(&mut self, seq: u32, msg: &[u8]) -> Result<(), FixStreamException>
+    {
+        let payload = self.append(MESSAGE, seq, msg)?;
+        self.index.insert(seq, (payload, msg.len()));
+        Ok(())
+    }
+
+    fn get(&self, seq: u32) -> Option<Vec<u8>>
+    {
+        self.index.get(&seq).map(|&(pos, len)| self.map[pos..pos + len].to_vec())
+    }
+
+    fn reset(&mut self) -> Result<(), FixStreamException>
+    {
+        // crash half way leaves no MAGIC (a fresh journal as well)
+        // or MAGIC in front of the zeroed tail
+        self.init()
+    }
+}
+
+impl Drop for JournalStore {
+    fn drop(&mut self)
+    {
+        if self.durability != Durability::Os {
+            let _ = self.sync();
+        }
+    }
+}
+
+fn io_error(path: &Path, e: ::std::io::Error) -> FixStreamException
+{
+    format!("{}: {}", path.display(), e)
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32
+{
+    buf[pos] as u32 | (buf[pos + 1] as u32) << 8 | (buf[pos + 2] as u32) << 16 | (buf[pos + 3] as u32) << 24
+}
+
+fn write_u32(buf: &mut Vec<u8>, v: u32)
+{
+    buf.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+/// CRC-32 (IEEE) lookup table
+fn crc_table() -> [u32; 256]
+{
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut c = i as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+    table
+}
+
+fn crc32(table: &[u32; 256], data: &[u8]) -> u32
+{
+    !data.iter().fold(!0u32, |c, &b| table[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8))
+}
@@ -0,0 +1,263 @@
+/*!
+  FIX JSON encoding (FIX Trading Community "Encoding FIX using JSON").
+
+  {"Header": {"MsgType": "D", ...}, "Body": {"NoPartyIDs": [{"PartyID": "A"}], ...}, "Trailer": {}}
+
+  All values are encoded as JSON strings, groups are arrays of objects keyed by
+  the NoXXX field name, BodyLength and CheckSum are not part of the encoding.
+  Field names come from the dictionary, fields without a name are keyed by tag number.
+ */
+use fix::*;
+use util;
+use serde_json;
+use serde_json::{Map, Value};
+
+/// FixStream sink producing one JSON document per message
+pub struct FixJsonWriter<'d>
+{
+    dictionary: &'d FixDictionary,
+    msg_type: Vec<u8>,
+    fields: Vec<(u32, String)>,
+    json: Option<String>,
+}
+
+impl<'d> FixJsonWriter<'d>
+{
+    pub fn new(dictionary: &'d FixDictionary) -> FixJsonWriter<'d>
+    {
+        FixJsonWriter {
+            dictionary: dictionary,
+            msg_type: vec![],
+            fields: vec![],
+            json: None,
+        }
+    }
+
+    /// JSON of the last completed message
+    pub fn take(&mut self) -> Option<String>
+    {
+        self.json.take()
+    }
+
+    fn key(&self, tag: u32) -> String
+    {
+        match self.dictionary.field_name(tag) {
+            Some(name) => name.to_string(),
+            None => tag.to_string(),
+        }
+    }
+
+    /// Collects fields starting at `pos` into `to` until a field that is not
+    /// a member of `group` is met (the whole remainder if group is None)
+    fn collect(&self, pos: &mut usize, group: Option<(u32, u32)>, to: &mut Map<String, Value>)
+    {
+        while *pos < self.fields.len() {
+            let tag = self.fields[*pos].0;
+            if let Some((no_tag, delimiter)) = group {
+                if tag == delimiter && !to.is_empty() {
+                    // next entry
+                    return;
+                }
+                if tag != delimiter && !self.dictionary.group_member(no_tag, tag) {
+                    return;
+                }
+            }
+            *pos += 1;
+            match self.dictionary.group_delimiter(tag) {
+                Some(delimiter) => {
+                    let mut entries = vec![];
+                    while *pos < self.fields.len() && self.fields[*pos].0 == delimiter {
+                        let mut entry = Map::new();
+                        self.collect(pos, Some((tag, delimiter)), &mut entry);
+                        entries.push(Value::Object(entry));
+                    }
+                    to.insert(self.key(tag), Value::Array(entries));
+                },
+                None => {
+                    to.insert(self.key(tag), Value::String(self.fields[*pos - 1].1.clone()));
+                },
+            }
+        }
+    }
+}
+
+impl<'d> FixTagHandler for FixJsonWriter<'d>
+{
+    fn tag_value(&mut self, t: u32, v: &[u8])
+    {
+        self.fields.push((t, String::from_utf8_lossy(v).into_owned()));
+    }
+}
+
+impl<'d> FixStream for FixJsonWriter<'d>
+{
+    type MSG_TYPES = ();
+
+    fn fix_message_start(&mut self, msg_type: FixMsgType<Self::MSG_TYPES>, is_replayable: bool)
+    {
+        self.msg_type = msg_type.as_bytes().to_vec();
+        self.fields.clear();
+    }
+
+    fn fix_message_done(&mut self, res: Result<(), FixStreamException>)
+    {
+        if res.is_err() {
+            self.fields.clear();
+            return;
+        }
+
+        let mut header = Map::new();
+        let mut body = Map::new();
+        let mut trailer = Map::new();
+        header.insert(self.key(35), Value::String(String::from_utf8_lossy(&self.msg_type).into_owned()));
+
+        let mut pos = 0;
+        while pos < self.fields.len() {
+            let tag = self.fields[pos].0;
+            match tag {
+                9 | 10 | 35 => pos += 1,
+                _ if self.dictionary.is_header_field(tag) => {
+                    header.insert(self.key(tag), Value::String(self.fields[pos].1.clone()));
+                    pos += 1;
+                },
+                _ if self.dictionary.is_trailer_field(tag) => {
+                    trailer.insert(self.key(tag), Value::String(self.fields[pos].1.clone()));
+                    pos += 1;
+                },
+                _ => {
+                    // body runs until trailer
+                    let mut end = pos;
+                    while end < self.fields.len() && !self.dictionary.is_trailer_field(self.fields[end].0) {
+                        end += 1;
+                    }
+                    let rest = self.fields.split_off(end);
+                    self.collect(&mut pos, None, &mut body);
+                    self.fields.extend(rest);
+                },
+            }
+        }
+
+        let mut msg = Map::new();
+        msg.insert("Header".to_string(), Value::Object(header));
+        msg.insert("Body".to_string(), Value::Object(body));
+        msg.insert("Trailer".to_string(), Value::Object(trailer));
+        self.json = serde_json::to_string(&Value::Object(msg)).ok();
+        self.fields.clear();
+    }
+}
+
+fn tag_of(dictionary: &FixDictionary, key: &str) -> Result<u32, FixStreamException>
+{
+    dictionary.field_tag(key)
+        .or_else(|| key.parse::<u32>().ok())
+        .ok_or_else(|| format!("Unknown field {}", key))
+}
+
+fn value_of(key: &str, v: &Value) -> Result<String, FixStreamException>
+{
+    match *v {
+        Value::String(ref s) => Ok(s.clone()),
+        Value::Number(ref n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(String::from(if b { "Y" } else { "N" })),
+        _ => Err(format!("Unexpected value of {}: {}", key, v)),
+    }
+}
+
+fn emit_fields<S>(dictionary: &FixDictionary, fields: &Map<String, Value>, skip: Option<u32>, stream: &mut S)
+    -> Result<(), FixStreamException>
+    where S: FixStream
+{
+    for (key, v) in fields {
+        let tag = tag_of(dictionary, key)?;
+        match tag {
+            8 | 9 | 10 | 35 => continue,
+            _ if Some(tag) == skip => continue,
+            _ => {},
+        }
+        match *v {
+            Value::Array(ref entries) => {
+                stream.tag_value(tag, entries.len().to_string().as_bytes());
+                let delimiter = dictionary.group_delimiter(tag);
+                for entry in entries {
+                    let entry = match *entry {
+                        Value::Object(ref entry) => entry,
+                        _ => return Err(format!("Group {} entry is not an object", key)),
+                    };
+                    // delimiter has to go first regardless of the JSON order
+                    let delimiter_key = delimiter.map(|d| {
+                        entry.keys().find(|k| tag_of(dictionary, k).ok() == Some(d)).cloned()
+                    });
+                    match delimiter_key {
+                        Some(Some(k)) => {
+                            stream.tag_value(delimiter.unwrap(), value_of(&k, &entry[&k])?.as_bytes());
+                            emit_fields(dictionary, entry, delimiter, stream)?;
+                        },
+                        Some(None) => {
+                            return Err(format!("Group {} entry misses delimiter {}", key, delimiter.unwrap()));
+                        },
+                        None => emit_fields(dictionary, entry, None, stream)?,
+                    }
+                }
+            },
+            _ => stream.tag_value(tag, value_of(key, v)?.as_bytes()),
+        }
+    }
+    Ok(())
+}
+
+/// Feeds JSON encoded message into the stream the same way tag-value parser does:
+/// fix_message_start, tag_value for every field except BeginString, BodyLength,
+/// MsgType and CheckSum, fix_message_done
+pub fn read_fix_json<S>(json: &str, dictionary: &FixDictionary, stream: &mut S) -> Result<(), FixStreamException>
+    where S: FixStream
+{
+    let msg: Value = serde_json::from_str(json).map_err(|e| format!("Malformed JSON: {}", e))?;
+    let empty = Map::new();
+    let header = section(&msg, "Header", &empty)?;
+    let body = section(&msg, "Body", &empty)?;
+    let trailer = section(&msg, "Trailer", &empty)?;
+
+    let msg_type = header.iter()
+        .find(|&(k, _)| tag_of(dictionary, k).ok() == Some(35))
+        .ok_or_else(|| String::from("Missing MsgType"))
+        .and_then(|(k, v)| value_of(k, v))?;
+
+    stream.fix_message_start(FixMsgType::from(msg_type.as_bytes()), true);
+    let mut res = Ok(());
+    for fields in &[header, body, trailer] {
+        res = emit_fields(dictionary, fields, None, stream);
+        if res.is_err() {
+            break;
+        }
+    }
+    stream.fix_message_done(res.clone());
+    res
+}
+
+fn section<'a>(msg: &'a Value, name: &str, empty: &'a Map<String, Value>) -> Result<&'a Map<String, Value>, FixStreamException>
+{
+    match msg.get(name) {
+        Some(&Value::Object(ref m)) => Ok(m),
+        Some(_) => Err(format!("{} is not an object", name)),
+        None => Ok(empty),
+    }
+}
+
+/// Converts first tag-value message in the buffer to JSON,
+/// returns JSON and the number of bytes consumed
+pub fn to_json(buf: &[u8], dictionary: &FixDictionary) -> Result<Option<(String, usize)>, FixStreamException>
+{
+    let mut w = FixJsonWriter::new(dictionary);
+    match util::parse_fix_message(buf, &mut w)? {
+        Some(len) => Ok(w.take().map(|json| (json, len))),
+        None => Ok(None),
+    }
+}
+
+/// Converts JSON message to tag-value using provided begin string
+pub fn from_json(json: &str, version: String, dictionary: &FixDictionary) -> Result<Vec<u8>, FixStreamException>
+{
+    let mut w = util::FixMessageWriter::<()>::new(version);
+    read_fix_json(json, dictionary, &mut w)?;
+    Ok(w.get_bytes().to_vec())
+}
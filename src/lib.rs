@@ -34,11 +34,24 @@
 ///
 
 extern crate futures;
+extern crate serde_json;
+extern crate xml;
+extern crate memmap;
 
 pub mod fix;
 pub mod connection;
 pub mod util;
 pub mod fix_tags;
+pub mod format;
+pub mod json;
+pub mod qfxml;
+pub mod fix_dict;
+pub mod dictionary;
+pub mod message;
+pub mod state;
+pub mod store;
+pub mod journal;
+pub mod schedule;
 mod test_util;
 
 #[cfg(test)]
@@ -47,6 +60,7 @@ mod test {
 	use std::fmt::Debug;
 	use super::util;
 	use super::test_util;
+	use super::{format, fix_tags, json, fix_dict, dictionary, message, state};
 	use std::cell::RefCell;
 	use std::cell::RefMut;
 	use std::rc::Rc;
@@ -54,7 +68,9 @@ mod test {
     use std::sync::atomic::{AtomicUsize, Ordering};
 	use test_util::{TestFixMessage, TestFixEnvironment};
 	use std::time::Duration;
-    use connection::{FixConnection, ConnectionType};
+    use connection::{FixConnection, ConnectionType, SeqResetPolicy, ReconnectPolicy, OutboundPolicy};
+    use store::{FixMessageStore, MemoryStore, FileStore};
+    use schedule::{SessionSchedule, TimeZone, Weekday, WEEKDAYS};
 
 	
 	pub struct TestScope2;
@@ -119,10 +135,8 @@ mod test {
 	#[test]
 	fn test_fix_logon_acceptor() {
 
-		let r = RefCell::new(test_util::TestFixRemote::new());
-        let (mut tft, env, mut fix_app) = test_util::fix_parts(&r);
-
-        let mut fc = FixConnection::new(String::from("FIX.4.2"), tft, env, ConnectionType::Acceptor);
+		// application messages are delivered once logon is accepted
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
 
         fc.connect(&mut fix_app);
 
@@ -131,6 +145,7 @@ mod test {
 		{
 			let mut rs = r.borrow_mut();
 			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
 			rs.tag_value(58, "Hello".to_string().as_bytes());
 			rs.fix_message_done(Ok(()));
 			
@@ -144,7 +159,12 @@ mod test {
 		let res = fc.read_fix_message(&mut fix_app);
 		// session level message not communicated
 		assert_eq!(None, fix_app.message.msg_type);
-		assert_eq!(Some(SessionRequest::In(SessionRequestType::Logon(None))), fix_app.requests.pop());
+		let logon = LogonFields {
+			heart_bt_int: 30,
+			custom: vec![(58, "Hello".to_string())],
+			..Default::default()
+		};
+		assert_eq!(Some(SessionRequest::In(SessionRequestType::Logon(logon))), fix_app.requests.pop());
 
 		let res = fc.read_fix_message(&mut fix_app);
 		// application level message communicated
@@ -155,10 +175,8 @@ mod test {
     #[test]
 	fn test_fix_logon_initiator() {
 
-		let r = RefCell::new(test_util::TestFixRemote::new());
-        let (mut tft, env, mut fix_app) = test_util::fix_parts(&r);
-
-        let mut fc = FixConnection::new(String::from("FIX.4.2"), tft, env, ConnectionType::Initiator);
+		// application messages are delivered once logon is accepted
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
 
         fc.connect(&mut fix_app);
 
@@ -167,6 +185,7 @@ mod test {
 		{
 			let mut rs = r.borrow_mut();
 			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
 			rs.tag_value(58, "Hello".to_string().as_bytes());
 			rs.fix_message_done(Ok(()));
 			
@@ -180,7 +199,12 @@ mod test {
 		let res = fc.read_fix_message(&mut fix_app);
 		// session level message not communicated
 		assert_eq!(None, fix_app.message.msg_type);
-		assert_eq!(Some(SessionRequest::In(SessionRequestType::Logon(None))), fix_app.requests.pop());
+		let logon = LogonFields {
+			heart_bt_int: 30,
+			custom: vec![(58, "Hello".to_string())],
+			..Default::default()
+		};
+		assert_eq!(Some(SessionRequest::In(SessionRequestType::Logon(logon))), fix_app.requests.pop());
 
 		let res = fc.read_fix_message(&mut fix_app);
 		// application level message communicated
@@ -188,12 +212,1576 @@ mod test {
 		assert_eq!(&"Hello".to_string(), fix_app.message.tag_values.get(&58).unwrap());
 	}
 
+	#[test]
+	fn test_fix_logon_validation() {
+		type Conn = FixConnection<test_util::TestFixTransport, TestFixEnvironment>;
+		type Remote = Rc<RefCell<test_util::TestFixRemote>>;
+		fn acceptor(version: &str) -> (Remote, Conn, test_util::TestFixApplication) {
+			let r = Rc::new(RefCell::new(test_util::TestFixRemote::new()));
+			let (tft, env, mut fix_app) = test_util::fix_parts(r.clone());
+			fix_app.auto_done = true;
+			let fc = FixConnection::new(String::from(version), tft, env, ConnectionType::Acceptor, Box::new(MemoryStore::new()))
+				.with_comp_ids("US", "THEM")
+				.with_heart_bt_int_bounds(5, 60);
+			(r, fc, fix_app)
+		}
+		fn send(r: &Remote, msg_type: FixMsgType<()>, fields: &[(u32, &[u8])]) {
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(msg_type, false);
+			for &(t, v) in fields {
+				rs.tag_value(t, v);
+			}
+			rs.fix_message_done(Ok(()));
+		}
+		fn logout_text(r: &Remote) -> String {
+			let sent = r.borrow_mut().sent_messages();
+			assert_eq!(Some("5".to_string()), sent.last().unwrap().msg_type);
+			assert!(r.borrow().disconnected);
+			sent.last().unwrap().tag_values[&58].clone()
+		}
+
+		// application message ahead of logon is not delivered
+		let (r, mut fc, mut fix_app) = acceptor("FIX.4.2");
+		fc.connect(&mut fix_app);
+		send(&r, FixMsgType::Unknown(b"TT"), &[(49, b"THEM"), (56, b"US"), (58, b"early")]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("First message is not Logon: TT", logout_text(&r));
+		assert_eq!(None, fix_app.message.msg_type);
+
+		let (r, mut fc, mut fix_app) = acceptor("FIX.4.4");
+		fc.connect(&mut fix_app);
+		send(&r, FixMsgType::Logon, &[(49, b"THEM"), (56, b"US"), (108, b"30")]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("Incorrect BeginString FIX.4.2, expecting FIX.4.4", logout_text(&r));
+		assert!(fix_app.requests.is_empty());
+
+		let (r, mut fc, mut fix_app) = acceptor("FIX.4.2");
+		fc.connect(&mut fix_app);
+		send(&r, FixMsgType::Logon, &[(49, b"OTHER"), (56, b"US"), (108, b"30")]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("Incorrect CompID 49=OTHER", logout_text(&r));
+		assert!(fix_app.requests.is_empty());
+
+		let (r, mut fc, mut fix_app) = acceptor("FIX.4.2");
+		fc.connect(&mut fix_app);
+		send(&r, FixMsgType::Logon, &[(49, b"THEM"), (56, b"US"), (108, b"120")]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("HeartBtInt 120 out of range [5, 60]", logout_text(&r));
+		assert!(fix_app.requests.is_empty());
+
+		// CompIDs are stamped on outgoing messages, checked on every incoming one
+		let (r, mut fc, mut fix_app) = acceptor("FIX.4.2");
+		fc.connect(&mut fix_app);
+		send(&r, FixMsgType::Logon, &[(49, b"THEM"), (56, b"US"), (108, b"30")]);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("US", sent[0].tag_values[&49]);
+		assert_eq!("THEM", sent[0].tag_values[&56]);
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+
+		send(&r, FixMsgType::Unknown(b"TT"), &[(49, b"THEM"), (56, b"YOU")]);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("3".to_string()), sent[0].msg_type);
+		assert_eq!("9", sent[0].tag_values[&373]);
+		assert_eq!("56", sent[0].tag_values[&371]);
+		assert_eq!(Some("5".to_string()), sent[1].msg_type);
+		assert!(r.borrow().disconnected);
+		assert_eq!(None, fix_app.message.msg_type);
+	}
+
+	#[test]
+	fn test_fix_session_payloads() {
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		fc.connect(&mut fix_app);
+
+		// outgoing logon carries the request payload
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("30", sent[0].tag_values[&108]);
+
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
+			rs.fix_message_done(Ok(()));
+
+			rs.fix_message_start(FixMsgType::ResendRequest, false);
+			rs.tag_value(7, b"5");
+			rs.tag_value(16, b"0");
+			rs.fix_message_done(Ok(()));
+
+			rs.fix_message_start(FixMsgType::SeqReset, false);
+			rs.tag_value(123, b"Y");
+			rs.tag_value(36, b"12");
+			rs.fix_message_done(Ok(()));
+		}
+
+		fc.read_fix_message(&mut fix_app);
+		fc.read_fix_message(&mut fix_app);
+		fc.read_fix_message(&mut fix_app);
+
+		let reset = SeqResetFields { gap_fill_flag: true, new_seq_no: 12 };
+		assert_eq!(Some(SessionRequest::In(SessionRequestType::SeqReset(reset))), fix_app.requests.pop());
+		let resend = ResendRequestFields { begin_seq_no: 5, end_seq_no: 0 };
+		assert_eq!(Some(SessionRequest::In(SessionRequestType::ResendRequest(resend))), fix_app.requests.pop());
+	}
+
+	#[test]
+	fn test_fix_logon_rejected() {
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
+		fix_app.reject_logon = Some(LogoutFields::rejected(SessionStatus::InvalidUsernameOrPassword, "Bad password"));
+		fc.connect(&mut fix_app);
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
+			rs.tag_value(553, b"user");
+			rs.tag_value(554, b"secret");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+
+		match fix_app.requests.pop() {
+			Some(SessionRequest::In(SessionRequestType::Logon(logon))) => {
+				assert_eq!(Some("user".to_string()), logon.username);
+				assert_eq!(Some("secret".to_string()), logon.password);
+			},
+			r => panic!("Unexpected request {:?}", r),
+		}
+
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("5".to_string()), sent[0].msg_type);
+		assert_eq!("Bad password", sent[0].tag_values[&58]);
+		assert_eq!("5", sent[0].tag_values[&1409]);
+		assert!(r.borrow().disconnected);
+
+		let events: Vec<state::SessionEvent> = fc.session_state().log().iter().map(|t| t.event).collect();
+		assert_eq!(vec![state::SessionEvent::Connected, state::SessionEvent::LogoutSent, state::SessionEvent::Disconnected], events);
+	}
+
+	#[test]
+	fn test_fix_outgoing_seq() {
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		fc.connect(&mut fix_app);
+		assert_eq!(2, fc.get_next_outgoing_seq());
+		r.borrow_mut().logon();
+		fc.read_fix_message(&mut fix_app);
+
+		fc.force_next_outgoing_seq(10);
+		{
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(b"D"), true);
+			out.tag_value(11, b"ORD1");
+			out.fix_message_done(Ok(()));
+		}
+		// gap fill keeps the sequence number it replaces
+		fc.send_gap_fill(3, 10);
+		assert_eq!(11, fc.get_next_outgoing_seq());
+
+		// BodyLength counts everything between BodyLength and CheckSum
+		{
+			let rs = r.borrow();
+			let msg = String::from_utf8_lossy(&rs.sent).into_owned();
+			let body_start = msg.find("35=").unwrap();
+			let body_end = msg.find("10=").unwrap();
+			assert!(msg.starts_with(&format!("8=FIX.4.2\x019={}\x01", body_end - body_start)));
+		}
+
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(3, sent.len());
+		assert_eq!("1", sent[0].tag_values[&34]);
+		assert_eq!("10", sent[1].tag_values[&34]);
+		assert_eq!(Some("4".to_string()), sent[2].msg_type);
+		assert_eq!("3", sent[2].tag_values[&34]);
+		assert_eq!("Y", sent[2].tag_values[&123]);
+		assert_eq!("10", sent[2].tag_values[&36]);
+		assert_eq!(21, sent[1].tag_values[&52].len());
+	}
+
+	#[test]
+	fn test_fix_incoming_seq() {
+		let (r, mut fc, mut fix_app) = test_util::logged_on(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
+		assert_eq!(2, fc.get_expected_incoming_seq());
+
+		let send = |seq: u32, text: &str, poss_dup: bool| {
+			let mut rs = r.borrow_mut();
+			rs.seq = seq;
+			rs.fix_message_start(FixMsgType::Unknown(b"TT"), false);
+			if poss_dup {
+				rs.tag_value(43, b"Y");
+				rs.tag_value(52, b"20170312-10:00:05");
+				rs.tag_value(122, b"20170312-10:00:00");
+			}
+			rs.tag_value(58, text.as_bytes());
+			rs.fix_message_done(Ok(()));
+		};
+
+		// gap: 5 is held until 3 and 4 are resent
+		send(5, "five", false);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(None, fix_app.message.msg_type);
+		assert_eq!(2, fc.get_expected_incoming_seq());
+		assert_eq!(FixOutState::Lagging, fc.session_state().out_state());
+
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("2".to_string()), sent[0].msg_type);
+		assert_eq!("2", sent[0].tag_values[&7]);
+		assert_eq!("4", sent[0].tag_values[&16]);
+
+		send(2, "two", true);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("two", fix_app.message.tag_values[&58]);
+		send(3, "three", true);
+		fc.read_fix_message(&mut fix_app);
+		// duplicate is ignored
+		send(3, "dup", true);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("three", fix_app.message.tag_values[&58]);
+		send(4, "four", true);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("five", fix_app.message.tag_values[&58]);
+		assert_eq!(6, fc.get_expected_incoming_seq());
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+
+		// PossDupFlag without OrigSendingTime is rejected, the number is used up
+		{
+			let mut rs = r.borrow_mut();
+			rs.seq = 6;
+			rs.fix_message_start(FixMsgType::Unknown(b"TT"), false);
+			rs.tag_value(43, b"Y");
+			rs.tag_value(58, b"six");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("3".to_string()), sent[0].msg_type);
+		assert_eq!("1", sent[0].tag_values[&373]);
+		assert_eq!("122", sent[0].tag_values[&371]);
+		assert_eq!("five", fix_app.message.tag_values[&58]);
+		assert!(!r.borrow().disconnected);
+		assert_eq!(7, fc.get_expected_incoming_seq());
+
+		// OrigSendingTime after SendingTime is rejected too, the session carries on
+		{
+			let mut rs = r.borrow_mut();
+			rs.seq = 7;
+			rs.fix_message_start(FixMsgType::Unknown(b"TT"), false);
+			rs.tag_value(43, b"Y");
+			rs.tag_value(52, b"20170312-10:00:00");
+			rs.tag_value(122, b"20170312-10:00:05");
+			rs.tag_value(58, b"seven");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("3".to_string()), sent[0].msg_type);
+		assert_eq!("10", sent[0].tag_values[&373]);
+		assert_eq!("five", fix_app.message.tag_values[&58]);
+		assert!(!r.borrow().disconnected);
+		assert_eq!(8, fc.get_expected_incoming_seq());
+
+		// too low without PossDupFlag terminates the session
+		send(3, "again", false);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("5".to_string()), sent[0].msg_type);
+		assert_eq!("MsgSeqNum too low, expecting 8 but received 3", sent[0].tag_values[&58]);
+		assert!(r.borrow().disconnected);
+		assert_eq!(8, fc.get_expected_incoming_seq());
+	}
+
+	#[test]
+	fn test_fix_heartbeat() {
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		fc.connect(&mut fix_app);
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"10");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(10, fc.heart_bt_int());
+		r.borrow_mut().sent_messages();
+		// session answers on its own, app only watches
+		fix_app.auto_done = false;
+
+		let msg_types = |fc: &mut FixConnection<test_util::TestFixTransport, TestFixEnvironment>, secs: u64| {
+			fc.timers().run_for(Duration::from_secs(secs));
+			fc.poll_timers();
+			r.borrow_mut().sent_messages().into_iter().map(|m| m.msg_type.unwrap()).collect::<Vec<String>>()
+		};
+
+		// idle for HeartBtInt, heartbeat is sent
+		assert_eq!(vec!["0".to_string()], msg_types(&mut fc, 10));
+		// nothing received for HeartBtInt + grace, counterparty is tested
+		fc.timers().run_for(Duration::from_secs(2));
+		fc.poll_timers();
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("1".to_string()), sent[0].msg_type);
+		let test_req_id = sent[0].tag_values[&112].clone();
+
+		// incoming TestRequest is answered, Heartbeat not carrying our TestReqID is no answer
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Heartbeat, false);
+			rs.fix_message_done(Ok(()));
+			rs.fix_message_start(FixMsgType::TestRequest, false);
+			rs.tag_value(112, b"PING");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("0".to_string()), sent[0].msg_type);
+		assert_eq!("PING", sent[0].tag_values[&112]);
+		// still waiting, no other TestRequest
+		assert_eq!(vec!["0".to_string()], msg_types(&mut fc, 12));
+
+		// answer resets the liveness check
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Heartbeat, false);
+			rs.tag_value(112, test_req_id.as_bytes());
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+
+		// TestRequest goes unanswered
+		assert_eq!(vec!["0".to_string()], msg_types(&mut fc, 10));
+		assert_eq!(vec!["1".to_string()], msg_types(&mut fc, 2));
+		assert!(!r.borrow().disconnected);
+		msg_types(&mut fc, 12);
+		assert!(r.borrow().disconnected);
+	}
+
+	#[test]
+	fn test_fix_resend() {
+		let (r, mut fc, mut fix_app) = test_util::logged_on(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		// resend does not wait for the app
+		fix_app.auto_done = false;
+
+		// 2 and 4 can be re-sent, 3 can not
+		for &(id, replayable) in &[("A", true), ("B", false), ("C", true)] {
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(b"D"), replayable);
+			out.tag_value(11, id.as_bytes());
+			out.fix_message_done(Ok(()));
+		}
+		let sent = r.borrow_mut().sent_messages();
+		let sending_time = sent[0].tag_values[&52].clone();
+
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::ResendRequest, false);
+			rs.tag_value(7, b"1");
+			rs.tag_value(16, b"999999");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+
+		let sent = r.borrow_mut().sent_messages();
+		let summary: Vec<(String, String, Option<String>)> = sent.iter()
+			.map(|m| (m.msg_type.clone().unwrap(), m.tag_values[&34].clone(), m.tag_values.get(&36).cloned()))
+			.collect();
+		assert_eq!(vec![
+			("4".to_string(), "1".to_string(), Some("2".to_string())),
+			("D".to_string(), "2".to_string(), None),
+			("4".to_string(), "3".to_string(), Some("4".to_string())),
+			("D".to_string(), "4".to_string(), None),
+		], summary);
+		assert_eq!("Y", sent[0].tag_values[&123]);
+		assert_eq!("Y", sent[1].tag_values[&43]);
+		assert_eq!(sending_time, sent[1].tag_values[&122]);
+		assert_eq!("A", sent[1].tag_values[&11]);
+		assert_eq!(5, fc.get_next_outgoing_seq());
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+
+		// nothing to resend below the first number
+		fc.force_next_outgoing_seq(0);
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::ResendRequest, false);
+			rs.tag_value(7, b"1");
+			rs.tag_value(16, b"0");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert!(!r.borrow().disconnected);
+	}
+
+	#[test]
+	fn test_fix_incoming_seq_reset() {
+		let (r, mut fc, mut fix_app) = test_util::logged_on(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
+
+		let reset = |seq: u32, gap_fill: bool, new_seq_no: &str| {
+			let mut rs = r.borrow_mut();
+			rs.seq = seq;
+			rs.fix_message_start(FixMsgType::SeqReset, false);
+			if gap_fill {
+				rs.tag_value(123, b"Y");
+			}
+			rs.tag_value(36, new_seq_no.as_bytes());
+			rs.fix_message_done(Ok(()));
+		};
+
+		reset(2, true, "5");
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(5, fc.get_expected_incoming_seq());
+		let gap_fill = SeqResetFields { gap_fill_flag: true, new_seq_no: 5 };
+		assert_eq!(Some(SessionRequest::In(SessionRequestType::SeqReset(gap_fill))), fix_app.requests.pop());
+
+		// reset mode does not care about MsgSeqNum
+		reset(1, false, "10");
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(10, fc.get_expected_incoming_seq());
+
+		reset(1, false, "8");
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(10, fc.get_expected_incoming_seq());
+		assert!(!r.borrow().disconnected);
+
+		let sent = r.borrow_mut().sent_messages();
+		let reject = sent.last().unwrap();
+		assert_eq!(Some("3".to_string()), reject.msg_type);
+		assert_eq!("1", reject.tag_values[&45]);
+		assert_eq!("36", reject.tag_values[&371]);
+		assert_eq!("5", reject.tag_values[&373]);
+	}
+
+	#[test]
+	fn test_fix_logout() {
+		let logged_on = |conn_type: ConnectionType| {
+			let (r, fc, mut fix_app) = test_util::connection(conn_type, Box::new(MemoryStore::new()));
+			let mut fc = fc.with_logout_timeout(Duration::from_secs(5));
+			test_util::log_on(&r, &mut fc, &mut fix_app);
+			(r, fc, fix_app)
+		};
+
+		// initiated logout, messages keep flowing until the reply
+		let (r, mut fc, mut fix_app) = logged_on(ConnectionType::Initiator);
+		fc.end_session(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("5".to_string()), sent[0].msg_type);
+		assert_eq!(FixOutState::Logout, fc.session_state().out_state());
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Unknown(b"TT"), false);
+			rs.tag_value(58, b"late");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!("late", fix_app.message.tag_values[&58]);
+		assert!(!r.borrow().disconnected);
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logout, false);
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert!(r.borrow().disconnected);
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert_eq!(FixOutState::Disconnected, fc.session_state().out_state());
+
+		// counterparty asks for a resend before it answers our Logout
+		let (r, mut fc, mut fix_app) = logged_on(ConnectionType::Initiator);
+		{
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(b"D"), true);
+			out.tag_value(11, b"ORDER1");
+			out.fix_message_done(Ok(()));
+		}
+		fc.end_session(&mut fix_app);
+		r.borrow_mut().sent_messages();
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::ResendRequest, false);
+			rs.tag_value(7, b"2");
+			rs.tag_value(16, b"0");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(2, sent.len());
+		assert_eq!(Some("D".to_string()), sent[0].msg_type);
+		assert_eq!("2", sent[0].tag_values[&34]);
+		assert_eq!("Y", sent[0].tag_values[&43]);
+		// our Logout is gap filled
+		assert_eq!(Some("4".to_string()), sent[1].msg_type);
+		assert_eq!("3", sent[1].tag_values[&34]);
+		assert_eq!("4", sent[1].tag_values[&36]);
+		assert!(!r.borrow().disconnected);
+		assert_eq!(FixOutState::Logout, fc.session_state().out_state());
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logout, false);
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert!(r.borrow().disconnected);
+		assert!(r.borrow_mut().sent_messages().is_empty());
+
+		// no reply within logout timeout
+		let (r, mut fc, mut fix_app) = logged_on(ConnectionType::Initiator);
+		fc.end_session(&mut fix_app);
+		fc.timers().run_for(Duration::from_secs(4));
+		fc.poll_timers();
+		assert!(!r.borrow().disconnected);
+		fc.timers().run_for(Duration::from_secs(1));
+		fc.poll_timers();
+		assert!(r.borrow().disconnected);
+
+		// counterparty logs out, reply and close even if the app never completes the request
+		let (r, mut fc, mut fix_app) = logged_on(ConnectionType::Acceptor);
+		fix_app.auto_done = false;
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logout, false);
+			rs.tag_value(58, b"bye");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("5".to_string()), sent[0].msg_type);
+		assert!(r.borrow().disconnected);
+	}
+
+	#[test]
+	fn test_fix_session_reject() {
+		let dd = Rc::new(dictionary::DataDictionary::load("spec/FIX42.xml").unwrap());
+		let (r, fc, mut fix_app) = test_util::connection(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
+		let mut fc = fc.with_dictionary(dd);
+		fc.connect(&mut fix_app);
+
+		let send = |msg_type: FixMsgType<()>, fields: &[(u32, &[u8])]| {
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(msg_type, false);
+			rs.tag_value(49, b"THEM");
+			rs.tag_value(56, b"US");
+			rs.tag_value(52, b"20170312-10:00:00");
+			for &(t, v) in fields {
+				rs.tag_value(t, v);
+			}
+			rs.fix_message_done(Ok(()));
+		};
+
+		send(FixMsgType::Logon, &[(98, b"0"), (108, b"30")]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+		r.borrow_mut().sent_messages();
+
+		// session message without required field is not acted upon
+		send(FixMsgType::TestRequest, &[]);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("3".to_string()), sent[0].msg_type);
+		assert_eq!("2", sent[0].tag_values[&45]);
+		assert_eq!("112", sent[0].tag_values[&371]);
+		assert_eq!("1", sent[0].tag_values[&372]);
+		assert_eq!("1", sent[0].tag_values[&373]);
+		assert_eq!(3, fc.get_expected_incoming_seq());
+
+		send(FixMsgType::ResendRequest, &[(7, b"x"), (16, b"0")]);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!("7", sent[0].tag_values[&371]);
+		assert_eq!("6", sent[0].tag_values[&373]);
+		assert_eq!(4, fc.get_expected_incoming_seq());
+
+		// dictionary validation, app gets the error instead of the message
+		send(FixMsgType::Unknown(b"W"), &[(262, b"R1"), (268, b"0")]);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!("4", sent[0].tag_values[&45]);
+		assert_eq!("55", sent[0].tag_values[&371]);
+		assert_eq!("W", sent[0].tag_values[&372]);
+		assert_eq!(None, fix_app.message.msg_type);
+		assert_eq!(5, fc.get_expected_incoming_seq());
+		assert_eq!(3, fix_app.rejects.len());
+		let (ref reject, ref error) = fix_app.rejects[2];
+		assert_eq!(4, reject.ref_seq_num);
+		assert_eq!((SessionRejectReason::RequiredTagMissing, Some(55)), (error.reason, error.tag));
+
+		send(FixMsgType::Unknown(b"W"), &[(262, b"R1"), (55, b"IBM"), (268, b"0")]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(Some("W".to_string()), fix_app.message.msg_type);
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert!(!r.borrow().disconnected);
+	}
+
+	#[test]
+	fn test_fix_reset_seq_num() {
+		let policy = SeqResetPolicy { on_logon: true, on_logout: true, on_disconnect: false };
+		let (r, fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		let mut fc = fc.with_seq_reset_policy(policy);
+		fc.force_next_outgoing_seq(10);
+		fc.force_expected_incoming_seq(20);
+
+		let logon = |seq: u32, reset: bool| {
+			let mut rs = r.borrow_mut();
+			rs.seq = seq;
+			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
+			if reset {
+				rs.tag_value(141, b"Y");
+			}
+			rs.fix_message_done(Ok(()));
+		};
+		let app_message = || {
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Unknown(b"TT"), false);
+			rs.fix_message_done(Ok(()));
+		};
+
+		// reset on logon
+		fc.connect(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!("1", sent[0].tag_values[&34]);
+		assert_eq!("Y", sent[0].tag_values[&141]);
+		logon(1, true);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert_eq!(2, fc.get_expected_incoming_seq());
+
+		app_message();
+		fc.read_fix_message(&mut fix_app);
+		fc.get_out_stream().fix_message_start(FixMsgType::Unknown(b"TT"), true);
+		fc.get_out_stream().fix_message_done(Ok(()));
+		r.borrow_mut().sent_messages();
+		assert_eq!((3, 3), (fc.get_expected_incoming_seq(), fc.get_next_outgoing_seq()));
+
+		// counterparty resets mid-session, we follow
+		logon(1, true);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("1", sent[0].tag_values[&34]);
+		assert_eq!("Y", sent[0].tag_values[&141]);
+		assert_eq!((2, 2), (fc.get_expected_incoming_seq(), fc.get_next_outgoing_seq()));
+
+		// replay of messages sent before the reset is not possible anymore
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::ResendRequest, false);
+			rs.tag_value(7, b"1");
+			rs.tag_value(16, b"0");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert!(sent.iter().all(|m| m.msg_type != Some("TT".to_string())));
+
+		// our mid-session reset, counterparty's Logon is the reply
+		fc.reset_session(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("1", sent[0].tag_values[&34]);
+		logon(1, true);
+		fc.read_fix_message(&mut fix_app);
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert_eq!((2, 2), (fc.get_expected_incoming_seq(), fc.get_next_outgoing_seq()));
+
+		// logon without the flag is not expected while logged on
+		logon(2, false);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("5".to_string()), sent[0].msg_type);
+		assert_eq!("Logon received while logged on", sent[0].tag_values[&58]);
+
+		// reset on logout
+		assert!(r.borrow().disconnected);
+		assert_eq!((1, 1), (fc.get_expected_incoming_seq(), fc.get_next_outgoing_seq()));
+	}
+
+	#[test]
+	fn test_fix_next_expected_seq() {
+		let (r, fc, mut fix_app) = test_util::connection(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
+		let mut fc = fc
+			.with_outbound_policy(OutboundPolicy::Persist)
+			.with_next_expected_msg_seq_num(true);
+		fc.connect(&mut fix_app);
+		for id in &["A", "B", "C"] {
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(b"TT"), true);
+			out.tag_value(11, id.as_bytes());
+			out.fix_message_done(Ok(()));
+		}
+		r.borrow_mut().sent_messages();
+
+		// counterparty has seen only the first one, the rest follows our Logon
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
+			rs.tag_value(789, b"2");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(3, sent.len());
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("4", sent[0].tag_values[&34]);
+		assert_eq!("2", sent[0].tag_values[&789]);
+		assert_eq!(("2", "B"), (&sent[1].tag_values[&34][..], &sent[1].tag_values[&11][..]));
+		assert_eq!(("3", "C"), (&sent[2].tag_values[&34][..], &sent[2].tag_values[&11][..]));
+		assert_eq!("Y", sent[2].tag_values[&43]);
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+
+		// counterparty expects more than we have ever sent
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
+		fc.connect(&mut fix_app);
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
+			rs.tag_value(789, b"10");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!("NextExpectedMsgSeqNum 10 is higher than expected 1", sent[0].tag_values[&58]);
+		assert!(r.borrow().disconnected);
+		assert!(fix_app.requests.is_empty());
+
+		// our Logon asked for the gap, no ResendRequest is needed
+		let (r, fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		let mut fc = fc.with_next_expected_msg_seq_num(true);
+		fc.force_expected_incoming_seq(3);
+		fc.connect(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!("3", sent[0].tag_values[&789]);
+		{
+			let mut rs = r.borrow_mut();
+			rs.seq = 5;
+			rs.fix_message_start(FixMsgType::Logon, false);
+			rs.tag_value(108, b"30");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert_eq!(FixOutState::Lagging, fc.session_state().out_state());
+		for seq in 3..5 {
+			let mut rs = r.borrow_mut();
+			rs.seq = seq;
+			rs.fix_message_start(FixMsgType::Unknown(b"TT"), false);
+			rs.tag_value(43, b"Y");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(6, fc.get_expected_incoming_seq());
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+	}
+
+	#[test]
+	fn test_fix_file_store() {
+		let nanos = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap().subsec_nanos();
+		let dir = ::std::env::temp_dir().join(format!("fixr-store-{}", nanos));
+		::std::fs::create_dir_all(&dir).unwrap();
+		let name = "FIX.4.2-US-THEM";
+
+		let created = {
+			let mut store = FileStore::open(&dir, name).unwrap();
+			assert_eq!((1, 1), (store.next_sender_seq(), store.next_target_seq()));
+			store.set(1, b"first").unwrap();
+			store.set(3, b"third").unwrap();
+			store.set_next_sender_seq(4).unwrap();
+			store.set_next_target_seq(7).unwrap();
+			store.creation_time()
+		};
+
+		// everything survives reopening
+		let mut store = FileStore::open(&dir, name).unwrap();
+		assert_eq!((4, 7), (store.next_sender_seq(), store.next_target_seq()));
+		assert_eq!(created, store.creation_time());
+		assert_eq!(Some(b"first".to_vec()), store.get(1));
+		assert_eq!(None, store.get(2));
+		assert_eq!(Some(b"third".to_vec()), store.get(3));
+
+		// stopped after the message was stored but before its number was
+		store.set(4, b"fourth").unwrap();
+		drop(store);
+		let mut store = FileStore::open(&dir, name).unwrap();
+		assert_eq!((5, 7), (store.next_sender_seq(), store.next_target_seq()));
+		assert!(!dir.join(format!("{}.seqnums.tmp", name)).exists());
+		drop(store);
+
+		// stopped half way through the header line, "5,16,5" cut to "5,16,2"
+		{
+			use std::io::Write;
+			let append = |ext: &str, data: &[u8]| {
+				let path = dir.join(format!("{}.{}", name, ext));
+				::std::fs::OpenOptions::new().append(true).open(path).unwrap().write_all(data).unwrap();
+			};
+			append("body", b"fifth");
+			append("header", b"5,16,2");
+		}
+		let mut store = FileStore::open(&dir, name).unwrap();
+		assert_eq!(None, store.get(5));
+		assert_eq!((5, 7), (store.next_sender_seq(), store.next_target_seq()));
+		store.set(5, b"fifth again").unwrap();
+		drop(store);
+		let mut store = FileStore::open(&dir, name).unwrap();
+		assert_eq!(Some(b"fifth again".to_vec()), store.get(5));
+		assert_eq!(Some(b"fourth".to_vec()), store.get(4));
+
+		store.reset().unwrap();
+		assert_eq!((1, 1), (store.next_sender_seq(), store.next_target_seq()));
+		assert_eq!(None, store.get(1));
+		drop(store);
+
+		// connection continues the stored session after restart
+		{
+			let (r, mut fc, _) = test_util::logged_on(ConnectionType::Acceptor, Box::new(FileStore::open(&dir, name).unwrap()));
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(b"D"), true);
+			out.tag_value(11, b"ORDER1");
+			out.fix_message_done(Ok(()));
+			assert_eq!("2", r.borrow_mut().sent_messages()[0].tag_values[&34]);
+		}
+
+		let (r, mut fc, mut fix_app) = test_util::logged_on(ConnectionType::Acceptor, Box::new(FileStore::open(&dir, name).unwrap()));
+		assert_eq!((3, 4), (fc.get_expected_incoming_seq(), fc.get_next_outgoing_seq()));
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::ResendRequest, false);
+			rs.tag_value(7, b"2");
+			rs.tag_value(16, b"2");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!("ORDER1", sent[0].tag_values[&11]);
+		assert_eq!("Y", sent[0].tag_values[&43]);
+
+		// session does not go on with its numbers not persisted
+		::std::fs::remove_dir_all(&dir).unwrap();
+		let out = fc.get_out_stream();
+		out.fix_message_start(FixMsgType::Unknown(b"D"), true);
+		out.tag_value(11, b"ORDER2");
+		out.fix_message_done(Ok(()));
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert!(r.borrow().disconnected);
+	}
+
+	#[test]
+	fn test_fix_journal_store() {
+		use journal::{JournalStore, Durability};
+		use std::fs::File;
+		use std::io::{Read, Write};
+
+		let nanos = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap().subsec_nanos();
+		let dir = ::std::env::temp_dir().join(format!("fixr-journal-{}", nanos));
+		::std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("session.journal");
+
+		// journal length after every operation with the state it stands for:
+		// (len, next sender, next target, messages stored)
+		let mut checkpoints = vec![];
+		{
+			// small chunk, the file has to grow a few times
+			let mut j = JournalStore::open_with_chunk(&path, Durability::Batched(3), 64).unwrap();
+			checkpoints.push((j.len(), 1, 1, 0));
+			for seq in 1..6 {
+				j.set(seq, format!("message {}", seq).as_bytes()).unwrap();
+				// stored message is never sent again under its number
+				checkpoints.push((j.len(), seq + 1, seq, seq));
+				j.set_next_sender_seq(seq + 1).unwrap();
+				checkpoints.push((j.len(), seq + 1, seq, seq));
+				j.set_next_target_seq(seq + 1).unwrap();
+				checkpoints.push((j.len(), seq + 1, seq + 1, seq));
+			}
+		}
+		let mut data = vec![];
+		File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+		let full = checkpoints.last().unwrap().0;
+
+		let open_cut = |data: &[u8]| {
+			let cut = dir.join("cut.journal");
+			File::create(&cut).unwrap().write_all(data).unwrap();
+			JournalStore::open_with_chunk(&cut, Durability::PerMessage, 64).unwrap()
+		};
+
+		// crash at any byte recovers the last complete record
+		let check_cuts = |data: &[u8], checkpoints: &[(usize, u32, u32, u32)]| {
+			let full = checkpoints.last().unwrap().0;
+			for off in 0..full + 1 {
+				let expected = checkpoints.iter().rev().find(|c| c.0 <= off).map(|c| (c.1, c.2, c.3)).unwrap_or((1, 1, 0));
+				let mut j = open_cut(&data[..off]);
+				assert_eq!((expected.0, expected.1), (j.next_sender_seq(), j.next_target_seq()), "offset {}", off);
+				for seq in 1..6 {
+					let msg = j.get(seq);
+					if seq <= expected.2 {
+						assert_eq!(Some(format!("message {}", seq).into_bytes()), msg, "offset {}", off);
+					} else {
+						assert_eq!(None, msg, "offset {}", off);
+					}
+				}
+
+				// torn tail is gone, new records survive reopening
+				j.set(expected.0, b"after crash").unwrap();
+				drop(j);
+				let j = JournalStore::open_with_chunk(dir.join("cut.journal"), Durability::PerMessage, 64).unwrap();
+				assert_eq!(Some(b"after crash".to_vec()), j.get(expected.0), "offset {}", off);
+				assert_eq!((expected.0 + 1, expected.1), (j.next_sender_seq(), j.next_target_seq()), "offset {}", off);
+			}
+		};
+		check_cuts(&data, &checkpoints);
+
+		// corrupted record and everything past it is dropped
+		let mut corrupt = data.clone();
+		// payload of the third message
+		let third = checkpoints[6].0 + 12;
+		corrupt[third] ^= 0xff;
+		let j = open_cut(&corrupt);
+		assert_eq!((3, 3), (j.next_sender_seq(), j.next_target_seq()));
+		assert_eq!(Some(b"message 2".to_vec()), j.get(2));
+		assert_eq!(None, j.get(3));
+		drop(j);
+
+		// reset crashed once MAGIC is cleared, old records are still there
+		let mut torn = data.clone();
+		for b in &mut torn[..8] {
+			*b = 0;
+		}
+		let j = open_cut(&torn);
+		assert_eq!((1, 1), (j.next_sender_seq(), j.next_target_seq()));
+		assert_eq!(None, j.get(1));
+		drop(j);
+
+		let mut j = JournalStore::open(&path, Durability::Os).unwrap();
+		assert_eq!(full, j.len());
+		j.reset().unwrap();
+		assert_eq!((1, 1), (j.next_sender_seq(), j.next_target_seq()));
+		assert_eq!(None, j.get(1));
+		drop(j);
+
+		// crash at any byte after reset never brings back the old session
+		let mut checkpoints = vec![];
+		{
+			let mut j = JournalStore::open_with_chunk(&path, Durability::Batched(3), 64).unwrap();
+			assert_eq!((1, 1), (j.next_sender_seq(), j.next_target_seq()));
+			checkpoints.push((j.len(), 1, 1, 0));
+			for seq in 1..3 {
+				j.set(seq, format!("message {}", seq).as_bytes()).unwrap();
+				checkpoints.push((j.len(), seq + 1, 1, seq));
+				j.set_next_sender_seq(seq + 1).unwrap();
+				checkpoints.push((j.len(), seq + 1, 1, seq));
+			}
+		}
+		let mut data = vec![];
+		File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+		check_cuts(&data, &checkpoints);
+
+		::std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_fix_session_schedule() {
+		use std::time::{SystemTime, UNIX_EPOCH};
+		// Monday
+		let jan_1 = UNIX_EPOCH + Duration::from_secs(1514764800);
+		let jul_2 = UNIX_EPOCH + Duration::from_secs(1530489600);
+		let hours = |h: u64| Duration::from_secs(h * 3600);
+		let mins = |m: u64| Duration::from_secs(m * 60);
+
+		let london = SessionSchedule::daily("08:00", "17:30").unwrap()
+			.with_days(WEEKDAYS)
+			.with_time_zone(TimeZone::named("Europe/London").unwrap());
+		assert!(!london.is_active(jan_1 + hours(8) - mins(1)));
+		assert!(london.is_active(jan_1 + hours(8)));
+		assert!(!london.is_active(jan_1 + hours(17) + mins(30)));
+		// summer time, 08:00 in London is 07:00 UTC
+		assert!(london.is_active(jul_2 + hours(7)));
+		assert!(!london.is_active(jul_2 + hours(17)));
+		// Saturday
+		assert!(!london.is_active(jan_1 + hours(5 * 24 + 12)));
+		assert_eq!(Some(jan_1 + hours(7 * 24 + 8)), london.next_change(jan_1 + hours(4 * 24 + 18)));
+
+		let weekly = SessionSchedule::weekly(Weekday::Sunday, "22:00", Weekday::Friday, "22:00").unwrap();
+		assert!(weekly.is_active(jan_1 + hours(2 * 24)));
+		assert!(!weekly.is_active(jan_1 + hours(5 * 24 + 12)));
+		assert_eq!(Some((jan_1 - hours(2), jan_1 + hours(4 * 24 + 22))), weekly.period(jan_1 + hours(3)));
+		assert_eq!(Some(jan_1 + hours(6 * 24 + 22)), weekly.next_change(jan_1 + hours(5 * 24 + 12)));
+		assert!(SessionSchedule::daily("8", "17:30").is_err());
+
+		// initiator logs on at 08:00 and out at 17:30
+		let r = Rc::new(RefCell::new(test_util::TestFixRemote::new()));
+		let (tft, mut env, mut fix_app) = test_util::fix_parts(r.clone());
+		fix_app.auto_done = true;
+		env.set_now(jan_1 + hours(7));
+		let clock = env.clock();
+		let mut fc = FixConnection::new(String::from("FIX.4.2"), tft, env, ConnectionType::Initiator, Box::new(MemoryStore::new()))
+			.with_schedule(london.clone())
+			.with_clock(Box::new(clock));
+		fc.poll_schedule(&mut fix_app);
+		fc.connect(&mut fix_app);
+		assert_eq!(FixOutState::Disconnected, fc.session_state().out_state());
+		assert!(r.borrow_mut().sent_messages().is_empty());
+
+		fc.timers().run_for(hours(1));
+		fc.poll_schedule(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("1", sent[0].tag_values[&34]);
+		r.borrow_mut().logon();
+		fc.read_fix_message(&mut fix_app);
+		assert!(fc.session_state().is_logged_on());
+
+		fc.timers().run_for(hours(9) + mins(30));
+		fc.poll_schedule(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("5".to_string()), sent.last().unwrap().msg_type);
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logout, false);
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(FixOutState::Disconnected, fc.session_state().out_state());
+		assert!(fc.store().next_sender_seq() > 2);
+
+		// Tuesday starts over
+		fc.timers().run_for(hours(14) + mins(30));
+		fc.poll_schedule(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("1", sent[0].tag_values[&34]);
+		assert_eq!(1, fc.get_expected_incoming_seq());
+
+		// acceptor refuses logon that comes too late
+		let r = Rc::new(RefCell::new(test_util::TestFixRemote::new()));
+		let (tft, mut env, mut fix_app) = test_util::fix_parts(r.clone());
+		fix_app.auto_done = true;
+		env.set_now(jan_1 + hours(17) + mins(29));
+		let clock = env.clock();
+		let mut fc = FixConnection::new(String::from("FIX.4.2"), tft, env, ConnectionType::Acceptor, Box::new(MemoryStore::new()))
+			.with_schedule(london)
+			.with_clock(Box::new(clock));
+		fc.connect(&mut fix_app);
+		fc.timers().run_for(mins(2));
+		r.borrow_mut().logon();
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("5".to_string()), sent[0].msg_type);
+		assert_eq!("7", sent[0].tag_values[&1409]);
+		assert!(r.borrow().disconnected);
+		assert!(fix_app.requests.is_empty());
+	}
+
+	#[test]
+	fn test_fix_reconnect() {
+		let secs = |s: u64| Duration::from_secs(s);
+		let primary = Some("primary:9001".to_string());
+		let backup = Some("backup:9002".to_string());
+		let policy = ReconnectPolicy {
+			initial_backoff: secs(1),
+			max_backoff: secs(4),
+			jitter: 0.0,
+			endpoints: vec!["primary:9001".to_string(), "backup:9002".to_string()],
+		};
+		let (r, fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		let mut fc = fc.with_reconnect(policy);
+
+		// counterparty is down, backoff doubles up to the max rotating endpoints
+		r.borrow_mut().refuse_connects = 3;
+		fc.connect(&mut fix_app);
+		assert_eq!(vec![ConnectionEvent::ConnectFailed { endpoint: primary.clone(), attempt: 1, retry_in: Some(secs(1)) }],
+				   fix_app.events);
+		fc.timers().run_for(secs(1));
+		fc.poll_reconnect(&mut fix_app);
+		assert_eq!(ConnectionEvent::ConnectFailed { endpoint: backup.clone(), attempt: 2, retry_in: Some(secs(2)) },
+				   fix_app.events[1]);
+		fc.timers().run_for(secs(1));
+		fc.poll_reconnect(&mut fix_app);
+		assert_eq!(2, r.borrow().endpoints.len());
+		fc.timers().run_for(secs(1));
+		fc.poll_reconnect(&mut fix_app);
+		assert_eq!(ConnectionEvent::ConnectFailed { endpoint: primary.clone(), attempt: 3, retry_in: Some(secs(4)) },
+				   fix_app.events[2]);
+		fc.timers().run_for(secs(4));
+		fc.poll_reconnect(&mut fix_app);
+		assert_eq!(ConnectionEvent::Connected { endpoint: backup.clone() }, fix_app.events[3]);
+		assert_eq!(vec!["primary:9001", "backup:9002", "primary:9001", "backup:9002"], r.borrow().endpoints);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		r.borrow_mut().logon();
+		fc.read_fix_message(&mut fix_app);
+		assert!(fc.session_state().is_logged_on());
+
+		// connection reset, same session carries on over the endpoint last used
+		fix_app.events.clear();
+		fc.error(String::from("Connection reset by peer"));
+		assert!(r.borrow().disconnected);
+		fc.timers().run_for(secs(1));
+		fc.poll_reconnect(&mut fix_app);
+		assert_eq!(vec![ConnectionEvent::ConnectionLost { retry_in: secs(1) },
+						ConnectionEvent::Reconnected { endpoint: backup.clone(), attempts: 0 }],
+				   fix_app.events);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("A".to_string()), sent[0].msg_type);
+		assert_eq!("2", sent[0].tag_values[&34]);
+
+		// no reconnect after logout
+		r.borrow_mut().logon();
+		fc.read_fix_message(&mut fix_app);
+		fix_app.events.clear();
+		fc.end_session(&mut fix_app);
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::Logout, false);
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(FixOutState::Disconnected, fc.session_state().out_state());
+		fc.timers().run_for(secs(60));
+		fc.poll_reconnect(&mut fix_app);
+		assert!(fix_app.events.is_empty());
+		assert_eq!(FixOutState::Disconnected, fc.session_state().out_state());
+	}
+
+	#[test]
+	fn test_fix_outbound_policy() {
+		let (r, fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		let mut fc = fc
+			.with_outbound_policy(OutboundPolicy::Persist)
+			.with_outbound_policy_for(b"D", OutboundPolicy::Queue)
+			.with_outbound_policy_for(b"F", OutboundPolicy::Reject);
+		fc.connect(&mut fix_app);
+		r.borrow_mut().sent_messages();
+
+		// logon is not complete yet
+		for &(msg_type, id) in &[("D", "Q1"), ("F", "R1"), ("G", "P1"), ("D", "Q2")] {
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(msg_type.as_bytes()), true);
+			out.tag_value(11, id.as_bytes());
+			out.fix_message_done(Ok(()));
+		}
+		assert!(r.borrow_mut().sent_messages().is_empty());
+		assert_eq!(2, fc.queued_messages());
+		assert!(fc.take_send_error().is_some());
+		assert!(fc.take_send_error().is_none());
+		// persisted message took its number
+		assert_eq!(3, fc.get_next_outgoing_seq());
+		assert!(fc.store().get(2).is_some());
+
+		r.borrow_mut().logon();
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(2, sent.len());
+		assert_eq!(("3", "Q1"), (&sent[0].tag_values[&34][..], &sent[0].tag_values[&11][..]));
+		assert_eq!(("4", "Q2"), (&sent[1].tag_values[&34][..], &sent[1].tag_values[&11][..]));
+		assert_eq!(0, fc.queued_messages());
+
+		// logged on, rejected type goes out as usual
+		{
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(b"F"), true);
+			out.tag_value(11, b"R2");
+			out.fix_message_done(Ok(()));
+		}
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!("5", sent[0].tag_values[&34]);
+		assert!(fc.take_send_error().is_none());
+
+		// persisted one is recovered through resend
+		{
+			let mut rs = r.borrow_mut();
+			rs.fix_message_start(FixMsgType::ResendRequest, false);
+			rs.tag_value(7, b"2");
+			rs.tag_value(16, b"2");
+			rs.fix_message_done(Ok(()));
+		}
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(1, sent.len());
+		assert_eq!(Some("G".to_string()), sent[0].msg_type);
+		assert_eq!(("2", "P1", "Y"), (&sent[0].tag_values[&34][..], &sent[0].tag_values[&11][..], &sent[0].tag_values[&43][..]));
+
+		// rejected unless configured otherwise
+		let (r, mut fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		fc.connect(&mut fix_app);
+		fc.get_out_stream().fix_message_start(FixMsgType::Unknown(b"G"), true);
+		fc.get_out_stream().fix_message_done(Ok(()));
+		assert!(fc.take_send_error().is_some());
+		assert_eq!(2, fc.get_next_outgoing_seq());
+
+		// persisted message not re-sent before the numbers start over is reported
+		let policy = SeqResetPolicy { on_logon: false, on_logout: false, on_disconnect: true };
+		let (r, fc, mut fix_app) = test_util::connection(ConnectionType::Initiator, Box::new(MemoryStore::new()));
+		let mut fc = fc
+			.with_outbound_policy(OutboundPolicy::Persist)
+			.with_seq_reset_policy(policy);
+		fc.connect(&mut fix_app);
+		fc.get_out_stream().fix_message_start(FixMsgType::Unknown(b"G"), true);
+		fc.get_out_stream().fix_message_done(Ok(()));
+		assert!(fc.take_send_error().is_none());
+		fc.error(String::from("Connection reset"));
+		assert_eq!(1, fc.get_next_outgoing_seq());
+		assert_eq!("1 persisted message(s) dropped by sequence reset, first MsgSeqNum 2", fc.take_send_error().unwrap());
+	}
+
+	#[test]
+	fn test_fix_session_state() {
+		use state::{SessionState, SessionEvent, SessionTransition};
+
+		let mut st = SessionState::new();
+		// no way to get logged on without connecting first
+		assert!(st.apply(SessionEvent::LogonAccepted).is_err());
+		assert_eq!((FixInState::Disconnected, FixOutState::Disconnected), (st.in_state(), st.out_state()));
+		assert!(st.log().is_empty());
+
+		st.apply(SessionEvent::Connected).unwrap();
+		st.apply(SessionEvent::LogonAccepted).unwrap();
+		assert!(st.is_logged_on());
+
+		st.apply(SessionEvent::GapDetected).unwrap();
+		assert_eq!(FixOutState::Lagging, st.out_state());
+		assert!(st.apply(SessionEvent::ResendDone).is_err());
+		// resend while lagging goes back to lagging, the gap is still open
+		st.apply(SessionEvent::ResendStarted).unwrap();
+		assert_eq!(FixOutState::Resending, st.out_state());
+		st.apply(SessionEvent::ResendDone).unwrap();
+		assert_eq!(FixOutState::Lagging, st.out_state());
+		st.apply(SessionEvent::GapFilled).unwrap();
+		st.apply(SessionEvent::ResendStarted).unwrap();
+		st.apply(SessionEvent::ResendDone).unwrap();
+		assert_eq!(FixOutState::Connected, st.out_state());
+
+		st.apply(SessionEvent::LogoutSent).unwrap();
+		assert_eq!((FixInState::Logout, FixOutState::Logout), (st.in_state(), st.out_state()));
+		assert!(st.apply(SessionEvent::LogonAccepted).is_err());
+		st.apply(SessionEvent::Disconnected).unwrap();
+
+		assert_eq!(10, st.log().len());
+		assert_eq!(SessionTransition {
+			event: SessionEvent::LogonAccepted,
+			from: (FixInState::Logon, FixOutState::Logon),
+			to: (FixInState::Connected, FixOutState::Connected),
+		}, st.log()[1]);
+		assert_eq!(10, st.take_log().len());
+		assert!(st.log().is_empty());
+
+		// counterparty's ResendRequest comes in while our ResendRequest is outstanding
+		let (r, mut fc, mut fix_app) = test_util::logged_on(ConnectionType::Acceptor, Box::new(MemoryStore::new()));
+		{
+			let out = fc.get_out_stream();
+			out.fix_message_start(FixMsgType::Unknown(b"D"), true);
+			out.tag_value(11, b"ORDER1");
+			out.fix_message_done(Ok(()));
+		}
+		let send = |seq: u32, msg_type: FixMsgType<()>, fields: &[(u32, &[u8])]| {
+			let mut rs = r.borrow_mut();
+			rs.seq = seq;
+			rs.fix_message_start(msg_type, false);
+			for &(t, v) in fields {
+				rs.tag_value(t, v);
+			}
+			rs.fix_message_done(Ok(()));
+		};
+		send(5, FixMsgType::Heartbeat, &[]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(FixOutState::Lagging, fc.session_state().out_state());
+		r.borrow_mut().sent_messages();
+		send(2, FixMsgType::ResendRequest, &[(7, b"2"), (16, b"0")]);
+		fc.read_fix_message(&mut fix_app);
+		let sent = r.borrow_mut().sent_messages();
+		assert_eq!(2, sent.len());
+		assert_eq!(Some("D".to_string()), sent[0].msg_type);
+		assert_eq!("Y", sent[0].tag_values[&43]);
+		// 3 and 4 are still missing
+		assert_eq!(FixOutState::Lagging, fc.session_state().out_state());
+		send(3, FixMsgType::Heartbeat, &[]);
+		fc.read_fix_message(&mut fix_app);
+		send(4, FixMsgType::Heartbeat, &[]);
+		fc.read_fix_message(&mut fix_app);
+		assert_eq!(6, fc.get_expected_incoming_seq());
+		assert_eq!(FixOutState::Connected, fc.session_state().out_state());
+		let events: Vec<SessionEvent> = fc.session_state().log().iter().map(|t| t.event).collect();
+		assert_eq!(&[SessionEvent::GapDetected, SessionEvent::ResendStarted,
+					 SessionEvent::ResendDone, SessionEvent::GapFilled], &events[events.len() - 4..]);
+	}
+
+	struct TestDictionary;
+
+	impl FixDictionary for TestDictionary {
+		fn group_delimiter(&self, no_tag: u32) -> Option<u32> {
+			match no_tag {
+				453 => Some(448),
+				_ => None,
+			}
+		}
+
+		fn group_member(&self, no_tag: u32, tag: u32) -> bool {
+			no_tag == 453 && (tag == 448 || tag == 447 || tag == 452)
+		}
+
+		fn field_name(&self, tag: u32) -> Option<&str> {
+			match tag {
+				453 => Some("NoPartyIDs"),
+				448 => Some("PartyID"),
+				_ => fix_tags::BuiltinTags.field_name(tag),
+			}
+		}
+
+		fn field_tag(&self, name: &str) -> Option<u32> {
+			match name {
+				"NoPartyIDs" => Some(453),
+				"PartyID" => Some(448),
+				_ => fix_tags::BuiltinTags.field_tag(name),
+			}
+		}
+	}
+
+	#[test]
+	fn test_fix_group_writer() {
+		let mut w = util::FixMessageWriter::<()>::new(String::from("FIX.4.2"));
+		w.set_dictionary(Rc::new(TestDictionary));
+		w.fix_message_start(FixMsgType::Unknown(b"D"), true);
+		w.group_start(453, None);
+		w.group_entry(448, b"A").unwrap();
+		w.tag_value(447, b"D");
+		w.group_entry(448, b"B").unwrap();
+		assert!(w.group_entry(447, b"D").is_err());
+		w.group_done().unwrap();
+		w.fix_message_done(Ok(()));
+		let msg = String::from_utf8_lossy(w.get_bytes()).into_owned();
+		assert!(msg.contains("453=2\x01448=A\x01447=D\x01448=B\x01"));
+
+		w.group_start(453, Some(2));
+		w.group_entry(448, b"A").unwrap();
+		assert!(w.group_done().is_err());
+
+		// group left open is closed when message is done, mismatch drops the message
+		let len = w.get_bytes().len();
+		w.fix_message_start(FixMsgType::Unknown(b"D"), true);
+		w.group_start(453, Some(2));
+		w.group_entry(448, b"A").unwrap();
+		assert!(w.message_done(Ok(())).is_err());
+		assert_eq!(len, w.get_bytes().len());
+	}
+
+	#[test]
+	fn test_fix_format() {
+		let arr = b"8=FIX.4.2\x019=20\x0135=8\x0139=2\x01150=F\x019999=X\x0110=000\x01";
+		let s = format::format_bytes(arr, &fix_tags::BuiltinTags);
+		assert!(s.contains("MsgType(35)=8 EXECUTION_REPORT\n"));
+		assert!(s.contains("OrdStatus(39)=2 FILLED\n"));
+		assert!(s.contains("ExecType(150)=F TRADE\n"));
+		assert!(s.contains("\n9999=X\n"));
+
+		let arr = b"35=D\x01453=2\x01448=A\x01447=D\x01448=B\x0155=IBM\x01";
+		let s = format::format_bytes(arr, &TestDictionary);
+		assert_eq!("MsgType(35)=D\nNoPartyIDs(453)=2\n  PartyID(448)=A\n  447=D\n  PartyID(448)=B\nSymbol(55)=IBM\n", s);
+	}
+
+	#[test]
+	fn test_fix_json() {
+		let mut w = util::FixMessageWriter::<()>::new(String::from("FIX.4.2"));
+		w.fix_message_start(FixMsgType::Unknown(b"D"), true);
+		w.tag_value(49, b"ME");
+		w.tag_value(11, b"ORD1");
+		w.group_start(453, None);
+		w.group_entry(448, b"A").unwrap();
+		w.tag_value(447, b"D");
+		w.group_entry(448, b"B").unwrap();
+		w.group_done().unwrap();
+		w.tag_value(55, b"IBM");
+		w.fix_message_done(Ok(()));
+
+		let (js, len) = json::to_json(w.get_bytes(), &TestDictionary).unwrap().unwrap();
+		assert_eq!(w.get_bytes().len(), len);
+		assert_eq!(r#"{"Header":{"MsgType":"D","SenderCompID":"ME"},"Body":{"ClOrdID":"ORD1","NoPartyIDs":[{"PartyID":"A","447":"D"},{"PartyID":"B"}],"Symbol":"IBM"},"Trailer":{}}"#, js);
+
+		// JSON goes through the same callbacks as tag-value
+		let mut msg = TestFixMessage::new();
+		json::read_fix_json(&js, &TestDictionary, &mut msg).unwrap();
+		assert_eq!(Some("D".to_string()), msg.msg_type);
+		assert_eq!(vec![49, 11, 453, 448, 447, 448, 55], msg.tag_ids);
+
+		let tv = json::from_json(&js, String::from("FIX.4.2"), &TestDictionary).unwrap();
+		assert_eq!(&w.get_bytes()[..], &tv[..]);
+	}
+
+	#[derive(Debug, PartialEq)]
+	enum TestAppMsgType {
+		TradeCaptureReport,
+		CollateralInquiryAck,
+	}
+
+	impl FixAppMsgType for TestAppMsgType {
+		fn lookup(btype: &[u8]) -> Option<Self> {
+			match btype {
+				b"AE" => Some(TestAppMsgType::TradeCaptureReport),
+				b"BZ" => Some(TestAppMsgType::CollateralInquiryAck),
+				_ => None,
+			}
+		}
+
+		fn as_bytes(&self) -> &[u8] {
+			match *self {
+				TestAppMsgType::TradeCaptureReport => b"AE",
+				TestAppMsgType::CollateralInquiryAck => b"BZ",
+			}
+		}
+	}
+
+	#[test]
+	fn test_fix_msg_type() {
+		let all: &[&[u8]] = &[b"0", b"1", b"2", b"3", b"4", b"5", b"A", b"j", b"AE", b"BZ", b"ZZ"];
+		for t in all {
+			let msg_type = FixMsgType::<TestAppMsgType>::from(*t);
+			assert_eq!(*t, msg_type.as_bytes());
+		}
+		match FixMsgType::<TestAppMsgType>::from(&b"BZ"[..]) {
+			FixMsgType::Custom(TestAppMsgType::CollateralInquiryAck) => {},
+			t => panic!("unexpected {:?}", t),
+		}
+		match FixMsgType::<TestAppMsgType>::from(&b"ZZ"[..]) {
+			FixMsgType::Unknown(b"ZZ") => {},
+			t => panic!("unexpected {:?}", t),
+		}
+		assert!(FixMsgType::is_session_level(&FixMsgType::<()>::from(&b"3"[..])));
+		assert!(!FixMsgType::is_session_level(&FixMsgType::<()>::from(&b"j"[..])));
+	}
+
+	#[test]
+	fn test_fix_dict() {
+		use fix_dict::{tags, fields, MsgType};
+		match FixMsgType::<MsgType>::from(&b"D"[..]) {
+			FixMsgType::Custom(MsgType::NewOrderSingle) => {},
+			t => panic!("unexpected {:?}", t),
+		}
+		assert_eq!(b"W", FixMsgType::Custom(MsgType::MarketDataSnapshotFullRefresh).as_bytes());
+		assert_eq!(54, tags::Side);
+		assert_eq!(Some(fields::Side::SellShort), fields::Side::from_bytes(b"5"));
+		assert_eq!(b"2", fields::OrdType::Limit.as_bytes());
+		assert_eq!(Some(super::qfxml::FieldType::Price), fix_dict::field_type(tags::Price));
+		assert_eq!(Some(269), fix_dict::group_delimiter(tags::NoMDEntries));
+		assert_eq!(Some("FILLED"), fix_dict::Dictionary.value_name(39, b"2"));
+	}
+
+	#[test]
+	fn test_fix_data_dictionary() {
+		use dictionary::{DataDictionary, FixValidator};
+		let dd = DataDictionary::load("spec/FIX42.xml").unwrap();
+		assert_eq!("FIX.4.2", dd.begin_string());
+		assert_eq!("Side", dd.field(54).unwrap().name);
+		assert_eq!(Some("BUY"), dd.value_name(54, b"1"));
+		assert!(dd.is_required(b"D", 11));
+		assert!(!dd.is_required(b"D", 44));
+		assert_eq!(269, dd.group(b"W", 268).unwrap().delimiter);
+		assert!(dd.is_valid_value(54, b"2"));
+		assert!(!dd.is_valid_value(54, b"Z"));
+
+		let header: &[(u32, &[u8])] = &[(49, b"ME"), (56, b"YOU"), (34, b"2"), (52, b"20170312-10:00:00")];
+		let mut fields = header.to_vec();
+		fields.extend_from_slice(&[(262, &b"R1"[..]), (55, b"IBM"), (268, b"2"), (269, b"0"), (270, b"1.5"), (269, b"1"), (270, b"1.6")]);
+		assert_eq!(Ok(()), dd.validate(b"W", &fields));
+
+		let check = |f: &[(u32, &[u8])], reason: SessionRejectReason, tag: u32| {
+			let mut fields = header.to_vec();
+			fields.extend_from_slice(f);
+			let err = dd.validate(b"W", &fields).unwrap_err();
+			assert_eq!((reason, Some(tag)), (err.reason, err.tag));
+		};
+		check(&[(268, b"0")], SessionRejectReason::RequiredTagMissing, 55);
+		check(&[(55, b"IBM"), (268, b"2"), (269, b"0"), (270, b"1.5")], SessionRejectReason::IncorrectNumInGroupCount, 268);
+		check(&[(55, b"IBM"), (268, b"1"), (269, b"0")], SessionRejectReason::RequiredTagMissing, 270);
+		check(&[(55, b"IBM"), (268, b"1"), (269, b"X"), (270, b"1")], SessionRejectReason::ValueIsIncorrect, 269);
+		check(&[(55, b"IBM"), (268, b"1"), (269, b"0"), (270, b"1.x")], SessionRejectReason::IncorrectDataFormat, 270);
+		check(&[(55, b"IBM"), (54, b"1"), (268, b"0")], SessionRejectReason::TagNotDefinedForMessageType, 54);
+		check(&[(55, b"IBM"), (55, b"IBM"), (268, b"0")], SessionRejectReason::TagAppearsMoreThanOnce, 55);
+		check(&[(55, b"IBM"), (9999, b"1"), (268, b"0")], SessionRejectReason::UndefinedTag, 9999);
+		assert_eq!(SessionRejectReason::InvalidMsgType, dd.validate(b"ZZ", header).unwrap_err().reason);
+
+		// SendingTime in seconds, milli-, micro-, nano- or picoseconds
+		let sending_time = |t: &[u8]| dd.validate(b"0", &[(49, &b"ME"[..]), (56, b"YOU"), (34, b"2"), (52, t)]);
+		for t in &["20170312-10:00:00", "20170312-10:00:00.123", "20170312-10:00:00.123456",
+				   "20170312-10:00:00.123456789", "20170312-10:00:00.123456789012"] {
+			assert_eq!(Ok(()), sending_time(t.as_bytes()), "{}", t);
+		}
+		for t in &["20170312-10:00", "20170312-10:00:00.", "20170312-10:00:00.1234", "20170312-10:00:00.123456789012345"] {
+			let err = sending_time(t.as_bytes()).unwrap_err();
+			assert_eq!((SessionRejectReason::IncorrectDataFormat, Some(52)), (err.reason, err.tag), "{}", t);
+		}
+
+		// through FixStream
+		let mut v = FixValidator::new(&dd);
+		v.fix_message_start(FixMsgType::Heartbeat, false);
+		for &(t, val) in header {
+			v.tag_value(t, val);
+		}
+		v.fix_message_done(Ok(()));
+		assert_eq!(Some(Ok(())), v.result());
+	}
+
+	#[test]
+	fn test_fix_typed_messages() {
+		use fix_dict::fields::{Side, OrdType, HandlInst, MDEntryType};
+		use fix_dict::messages::{NewOrderSingle, MarketDataSnapshotFullRefresh, MarketDataSnapshotFullRefreshNoMDEntries};
+		use message::FixMessage;
+
+		let mut order = NewOrderSingle::default();
+		order.cl_ord_id = "ORD1".to_string();
+		order.handl_inst = HandlInst::AutomatedExecutionOrderPrivate;
+		order.symbol = "IBM".to_string();
+		order.side = Side::Sell;
+		order.transact_time = "20170312-10:00:00".to_string();
+		order.ord_type = OrdType::Limit;
+		order.price = Some(101.5);
+
+		let mut w = util::FixMessageWriter::<()>::new(String::from("FIX.4.2"));
+		order.encode(&mut w);
+		assert!(String::from_utf8_lossy(w.get_bytes()).contains("35=D\x0111=ORD1\x0121=1\x0155=IBM\x0154=2\x01"));
+		let (decoded, _) = message::decode::<NewOrderSingle>(w.get_bytes()).unwrap().unwrap();
+		assert_eq!(order, decoded);
+		assert_eq!(None, decoded.account);
+
+		let mut md = MarketDataSnapshotFullRefresh::default();
+		md.symbol = "IBM".to_string();
+		for &(t, px) in &[(MDEntryType::Bid, 1.5), (MDEntryType::Offer, 1.6)] {
+			let mut e = MarketDataSnapshotFullRefreshNoMDEntries::default();
+			e.md_entry_type = t;
+			e.md_entry_px = px;
+			md.no_md_entries.push(e);
+		}
+		let mut w = util::FixMessageWriter::<()>::new(String::from("FIX.4.2"));
+		md.encode(&mut w);
+		let (decoded, _) = message::decode::<MarketDataSnapshotFullRefresh>(w.get_bytes()).unwrap().unwrap();
+		assert_eq!(md, decoded);
+
+		// missing required field
+		let mut w = util::FixMessageWriter::<()>::new(String::from("FIX.4.2"));
+		w.fix_message_start(FixMsgType::Unknown(b"W"), true);
+		w.tag_value(268, b"0");
+		w.fix_message_done(Ok(()));
+		assert!(message::decode::<MarketDataSnapshotFullRefresh>(w.get_bytes()).is_err());
+	}
+
 	#[test]
 	fn test_fix_environment() {
 		let env = &mut TestFixEnvironment::new();
-		let count = AtomicUsize::new(0);
-        let trigger = || {
-            count.fetch_add(1, Ordering::Relaxed);
+		let count = Arc::new(AtomicUsize::new(0));
+		let c = count.clone();
+        let trigger = move || {
+            c.fetch_add(1, Ordering::Relaxed);
 			println!("Timeout!");
 		};
 		let h = env.set_timeout(trigger, Duration::from_secs(10));
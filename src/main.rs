@@ -8,30 +8,52 @@ use std::thread;
 use std::thread::JoinHandle;
 use std::time::{Duration, SystemTime};
 use fixr::fix::*;
-use fixr::connection::{FixConnection, ConnectionType};
+use fixr::connection::{FixConnection, ConnectionType, ReconnectPolicy};
+use fixr::store::MemoryStore;
 use std::io::prelude::*;
 use std::ptr;
-use std::net::{TcpListener, TcpStream};
-use std::io::ErrorKind;
-use std::sync::atomic::AtomicUsize;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use tbr::*;
 use std::sync::{Arc, Mutex};
 
 pub struct TestTransport
 {
-    connector: Box<Fn() -> TcpStream>,
+    connector: Box<Fn(&str) -> io::Result<TcpStream>>,
+    endpoint: String,
+    // read and write failures, passed on to the connection by the main loop
+    errors: Sender<FixStreamException>,
     tcp: Option<Reader<TcpStream>>,
-    reader: Option<JoinHandle<()>>
+    reader: Option<JoinHandle<()>>,
+    // tells the reader thread of the current connection to quit
+    stop: Arc<AtomicBool>,
 }
 
 impl FixTransport for TestTransport
 {
-	fn connect<F>(mut self, mut on_success: F) where F: FnOnce(Self) -> (), Self: Sized
+	fn connect<F>(&mut self, mut on_success: F) where F: FnOnce(&mut Self) -> ()
 	{
-        let (r, w) = ThreadedBufReader::with_capacity((self.connector)(), 1024);
+        let stream = match (self.connector)(&self.endpoint) {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Connect to {} failed: {}", self.endpoint, e);
+                return;
+            }
+        };
+        let (r, w) = ThreadedBufReader::with_capacity(stream, 1024);
+        let errors = self.errors.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        self.stop = stop.clone();
         self.reader = Some(thread::spawn(move || {
-            loop {
-                w.fill_buf_local().unwrap();
+            while !stop.load(Ordering::SeqCst) {
+                if let Err(e) = w.fill_buf_local() {
+                    // failure of a socket shut down on purpose is no news
+                    if !stop.load(Ordering::SeqCst) {
+                        let _ = errors.send(format!("Read failed: {:?}", e));
+                    }
+                    return;
+                }
                 thread::sleep_ms(1);
             }
         }));
@@ -66,25 +88,43 @@ impl FixTransport for TestTransport
                 
                 let writer: &mut TcpStream = stream.into();
                 println!("Writing {:?}", buf);
-                writer.write(buf)
-                      .or_else(|err| {
-                          if err.kind() == ErrorKind::WouldBlock {
-                              Ok(0)
-                          }
-                          else { 
-                              Err(err) 
-                          } 
-                      }).unwrap()
+                match writer.write(buf) {
+                    Ok(len) => len,
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => 0,
+                    Err(err) => {
+                        let _ = self.errors.send(format!("Write failed: {}", err));
+                        0
+                    }
+                }
             })
 	}
 	
-    fn on_read<F>(self, mut on_success: F) where F: FnOnce(Self) -> (), Self: Sized
+    fn disconnect(&mut self)
+    {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(ref stream) = self.tcp {
+            // wakes up the reader thread blocked on the socket
+            let tcp: &mut TcpStream = stream.into();
+            let _ = tcp.shutdown(Shutdown::Both);
+        }
+        self.tcp = None;
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+
+    fn on_read<F>(&mut self, mut on_success: F) where F: FnOnce(&mut Self) -> ()
     {
         thread::spawn(move || {
             thread::sleep_ms(1000); // example sleep a second
         }).join();
         on_success(self);
     }
+
+    fn set_endpoint(&mut self, endpoint: &str)
+    {
+        self.endpoint = endpoint.to_string();
+    }
 }
 
 
@@ -95,7 +135,8 @@ pub struct TestFixTimerHandler
 
 impl FixTimerHandler for TestFixTimerHandler
 {
-	fn cancel(self) {
+	fn cancel(self: Box<Self>) {
+		let _ = self.cancel_signal.send(());
 	}
 }
 
@@ -151,6 +192,11 @@ impl FixApplication for ExamplFixApp {
         in_ch.read_fix_message(self);
     }
 
+    fn on_connection_event(&mut self, event: &ConnectionEvent)
+    {
+        println!("Connection event: {:?}", event);
+    }
+
     fn in_stream(&mut self) -> &mut Self::FIX_STREAM
     {
         &mut self.stream
@@ -204,8 +250,8 @@ fn main()
 
 	args.next(); // consume program name
 
-	if args.len() != 3 {
-		println!("usage: <ip> <port> <-c|-l>");
+	if args.len() < 3 {
+		println!("usage: <ip> <port> <-c|-l> [backup ip:port]...");
 		return;
 	}
 
@@ -216,17 +262,17 @@ fn main()
 	
     let mut timers = TestFixEnvironment {};
 
-    let (conn_type, stream): (ConnectionType, Box<Fn() -> TcpStream>) = match mode.as_str() {
+    let (conn_type, stream): (ConnectionType, Box<Fn(&str) -> io::Result<TcpStream>>) = match mode.as_str() {
 		"-c" => {
-            let stream_provider = move || {
-                TcpStream::connect(sockaddr).unwrap()
+            let stream_provider = |endpoint: &str| {
+                TcpStream::connect(endpoint)
             };
             (ConnectionType::Initiator, box stream_provider)
 		},
 		"-l" => {
             let listener = TcpListener::bind(sockaddr).unwrap();
-            let stream_provider = move || {
-                listener.accept().unwrap().0
+            let stream_provider = move |_: &str| {
+                listener.accept().map(|(stream, _)| stream)
             };
             (ConnectionType::Acceptor, box stream_provider)
 		},
@@ -235,12 +281,25 @@ fn main()
 		}
 	};
 
-    let transport = TestTransport { connector: stream, tcp: None, reader: None }; 
-    let mut conn = FixConnection::new("FIX4.2".to_string(), transport, timers, conn_type);
+    let primary = format!("{}:{}", sockaddr.0, sockaddr.1);
+    let (errors_tx, errors) = mpsc::channel();
+    let transport = TestTransport { connector: stream, endpoint: primary.clone(), errors: errors_tx, tcp: None, reader: None,
+                                     stop: Arc::new(AtomicBool::new(false)) }; 
+    let reconnect = ReconnectPolicy {
+        endpoints: Some(primary).into_iter().chain(args).collect(),
+        ..Default::default()
+    };
+    let mut conn = FixConnection::new("FIX4.2".to_string(), transport, timers, conn_type, box MemoryStore::new())
+        .with_reconnect(reconnect);
     let mut fix_app = ExamplFixApp { stream: TestFixStream {} };
     conn.connect(&mut fix_app);
 
 	loop {
 		thread::sleep_ms(100);
+		while let Ok(e) = errors.try_recv() {
+			conn.error(e);
+		}
+		conn.poll_timers();
+		conn.poll_reconnect(&mut fix_app);
 	}
 }
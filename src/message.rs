@@ -0,0 +1,153 @@
+/*!
+  Support for strongly typed messages generated by build.rs (fix_dict::messages).
+
+  Generated message decodes by acting as a FixStream sink and encodes by
+  writing itself into any FixStream:
+
+  let mut msg = NewOrderSingle::default();
+  util::parse_fix_message(buf, &mut msg);
+  msg.encode(connection.get_out_stream());
+ */
+use fix::*;
+use util;
+
+/// Conversion between typed field value and its tag-value representation
+pub trait FieldValue: Sized {
+    fn from_fix(v: &[u8]) -> Option<Self>;
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H);
+}
+
+impl FieldValue for String {
+    fn from_fix(v: &[u8]) -> Option<Self> { String::from_utf8(v.to_vec()).ok() }
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) { h.tag_value(tag, self.as_bytes()) }
+}
+
+impl FieldValue for Vec<u8> {
+    fn from_fix(v: &[u8]) -> Option<Self> { Some(v.to_vec()) }
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) { h.tag_value(tag, self) }
+}
+
+impl FieldValue for u32 {
+    fn from_fix(v: &[u8]) -> Option<Self> { util::parse_u32(v) }
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) { h.tag_value(tag, self.to_string().as_bytes()) }
+}
+
+impl FieldValue for i64 {
+    fn from_fix(v: &[u8]) -> Option<Self> { ::std::str::from_utf8(v).ok().and_then(|s| s.parse().ok()) }
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) { h.tag_value(tag, self.to_string().as_bytes()) }
+}
+
+impl FieldValue for f64 {
+    fn from_fix(v: &[u8]) -> Option<Self> { ::std::str::from_utf8(v).ok().and_then(|s| s.parse().ok()) }
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) { h.tag_value(tag, self.to_string().as_bytes()) }
+}
+
+impl FieldValue for char {
+    fn from_fix(v: &[u8]) -> Option<Self> { if v.len() == 1 { Some(v[0] as char) } else { None } }
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) { h.tag_value(tag, &[*self as u8]) }
+}
+
+impl FieldValue for bool {
+    fn from_fix(v: &[u8]) -> Option<Self> {
+        match v {
+            b"Y" => Some(true),
+            b"N" => Some(false),
+            _ => None,
+        }
+    }
+    fn write_fix<H: FixTagHandler + ?Sized>(&self, tag: u32, h: &mut H) { h.tag_value(tag, if *self { b"Y" } else { b"N" }) }
+}
+
+/// Decoding state carried by generated structs: currently open group,
+/// required fields seen so far and first decoding error
+#[derive(Debug, Clone, Default)]
+pub struct DecodeState {
+    pub group: Option<u32>,
+    seen: Vec<u32>,
+    error: Option<FixStreamException>,
+}
+
+impl DecodeState {
+    /// Stores decoded value, records error if value can not be decoded
+    pub fn set<V: FieldValue>(&mut self, tag: u32, v: &[u8], to: &mut V)
+    {
+        self.seen.push(tag);
+        match V::from_fix(v) {
+            Some(val) => *to = val,
+            None => self.invalid(tag, v),
+        }
+    }
+
+    pub fn set_opt<V: FieldValue>(&mut self, tag: u32, v: &[u8], to: &mut Option<V>)
+    {
+        self.seen.push(tag);
+        match V::from_fix(v) {
+            Some(val) => *to = Some(val),
+            None => self.invalid(tag, v),
+        }
+    }
+
+    /// NoXXX field met, following fields go to the group entries
+    pub fn group_start(&mut self, no_tag: u32)
+    {
+        self.seen.push(no_tag);
+        self.group = Some(no_tag);
+    }
+
+    fn invalid(&mut self, tag: u32, v: &[u8])
+    {
+        if self.error.is_none() {
+            self.error = Some(format!("Invalid value of {}: {:?}", tag, String::from_utf8_lossy(v)));
+        }
+    }
+
+    /// Message is complete, check required fields
+    pub fn done(&mut self, res: Result<(), FixStreamException>, required: &[u32])
+    {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = res {
+            self.error = Some(e);
+            return;
+        }
+        if let Some(tag) = required.iter().find(|t| !self.seen.contains(t)) {
+            self.error = Some(format!("Required tag {} missing", tag));
+        }
+    }
+
+    pub fn error(&self) -> Option<&FixStreamException>
+    {
+        self.error.as_ref()
+    }
+}
+
+/// Decoding state does not take part in message comparison
+impl PartialEq for DecodeState {
+    fn eq(&self, _: &DecodeState) -> bool { true }
+}
+
+/// Implemented by every generated message
+pub trait FixMessage: FixStream + Default {
+    /// MsgType(35) of the message
+    fn msg_type() -> &'static [u8];
+    /// Writes complete message (fix_message_start .. fix_message_done) into the stream
+    fn encode<S: FixStream>(&self, s: &mut S);
+    /// Error found while decoding, if any
+    fn decode_error(&self) -> Option<&FixStreamException>;
+}
+
+/// Decodes first message in the buffer, returns message and number of bytes consumed
+pub fn decode<M: FixMessage>(buf: &[u8]) -> Result<Option<(M, usize)>, FixStreamException>
+{
+    let mut msg = M::default();
+    match util::parse_fix_message(buf, &mut msg)? {
+        Some(len) => {
+            if let Some(e) = msg.decode_error() {
+                return Err(e.clone());
+            }
+            Ok(Some((msg, len)))
+        },
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,354 @@
+/*!
+  QuickFIX style XML data dictionary model (FIX42.xml, FIX44.xml and etc.)
+
+  Shared between build.rs code generator and runtime DataDictionary.
+ */
+use std::io::Read;
+use xml::reader::{EventReader, XmlEvent};
+
+/// FIX field data types as they appear in the dictionary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    Int,
+    Length,
+    SeqNum,
+    NumInGroup,
+    TagNum,
+    DayOfMonth,
+    Float,
+    Qty,
+    Price,
+    PriceOffset,
+    Amt,
+    Percentage,
+    Char,
+    Boolean,
+    String,
+    MultipleValueString,
+    MultipleCharValue,
+    Currency,
+    Exchange,
+    Country,
+    Language,
+    UtcTimestamp,
+    UtcTimeOnly,
+    UtcDateOnly,
+    LocalMktDate,
+    MonthYear,
+    Data,
+    XmlData,
+}
+
+impl FieldType {
+    pub fn from_str(t: &str) -> FieldType
+    {
+        match t {
+            "INT" => FieldType::Int,
+            "LENGTH" => FieldType::Length,
+            "SEQNUM" => FieldType::SeqNum,
+            "NUMINGROUP" => FieldType::NumInGroup,
+            "TAGNUM" => FieldType::TagNum,
+            "DAYOFMONTH" => FieldType::DayOfMonth,
+            "FLOAT" => FieldType::Float,
+            "QTY" | "QUANTITY" => FieldType::Qty,
+            "PRICE" => FieldType::Price,
+            "PRICEOFFSET" => FieldType::PriceOffset,
+            "AMT" => FieldType::Amt,
+            "PERCENTAGE" => FieldType::Percentage,
+            "CHAR" => FieldType::Char,
+            "BOOLEAN" => FieldType::Boolean,
+            "MULTIPLEVALUESTRING" | "MULTIPLESTRINGVALUE" => FieldType::MultipleValueString,
+            "MULTIPLECHARVALUE" => FieldType::MultipleCharValue,
+            "CURRENCY" => FieldType::Currency,
+            "EXCHANGE" => FieldType::Exchange,
+            "COUNTRY" => FieldType::Country,
+            "LANGUAGE" => FieldType::Language,
+            "UTCTIMESTAMP" | "TIME" => FieldType::UtcTimestamp,
+            "UTCTIMEONLY" => FieldType::UtcTimeOnly,
+            "UTCDATEONLY" | "UTCDATE" | "DATE" => FieldType::UtcDateOnly,
+            "LOCALMKTDATE" => FieldType::LocalMktDate,
+            "MONTHYEAR" => FieldType::MonthYear,
+            "DATA" => FieldType::Data,
+            "XMLDATA" => FieldType::XmlData,
+            // everything else is treated as string
+            _ => FieldType::String,
+        }
+    }
+
+    /// Name of the variant, used by code generator
+    pub fn variant(&self) -> String
+    {
+        format!("{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub number: u32,
+    pub name: String,
+    pub field_type: FieldType,
+    /// enum value and its description
+    pub values: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Member {
+    Field { name: String, required: bool },
+    Group { name: String, required: bool, members: Vec<Member> },
+    Component { name: String, required: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub name: String,
+    pub msg_type: String,
+    /// "admin" or "app"
+    pub msg_cat: String,
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Component {
+    pub name: String,
+    pub members: Vec<Member>,
+}
+
+/// Repeating group flattened from the dictionary
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub no_tag: u32,
+    pub delimiter: u32,
+    /// tags of the entry fields (including nested NoXXX tags but not their members)
+    pub members: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Spec {
+    pub begin_string: String,
+    pub header: Vec<Member>,
+    pub trailer: Vec<Member>,
+    pub messages: Vec<Message>,
+    pub components: Vec<Component>,
+    pub fields: Vec<Field>,
+}
+
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str>
+    {
+        self.attributes.iter().find(|a| a.0 == name).map(|a| a.1.as_str())
+    }
+
+    fn req_attr(&self, name: &str) -> Result<&str, String>
+    {
+        self.attr(name).ok_or_else(|| format!("<{}> misses attribute {}", self.name, name))
+    }
+
+    fn child(&self, name: &str) -> Option<&Element>
+    {
+        self.children.iter().find(|c| c.name == name)
+    }
+}
+
+fn read_tree<R: Read>(r: R) -> Result<Element, String>
+{
+    let mut stack: Vec<Element> = vec![];
+    for e in EventReader::new(r) {
+        match e {
+            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                stack.push(Element {
+                    name: name.local_name,
+                    attributes: attributes.into_iter().map(|a| (a.name.local_name, a.value)).collect(),
+                    children: vec![],
+                });
+            },
+            Ok(XmlEvent::EndElement { .. }) => {
+                let el = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(el),
+                    None => return Ok(el),
+                }
+            },
+            Ok(_) => {},
+            Err(e) => return Err(format!("Malformed dictionary: {}", e)),
+        }
+    }
+    Err(String::from("Malformed dictionary: no root element"))
+}
+
+fn read_members(el: &Element) -> Result<Vec<Member>, String>
+{
+    let mut members = vec![];
+    for c in &el.children {
+        let required = c.attr("required") == Some("Y");
+        let name = c.req_attr("name")?.to_string();
+        match c.name.as_str() {
+            "field" => members.push(Member::Field { name: name, required: required }),
+            "component" => members.push(Member::Component { name: name, required: required }),
+            "group" => members.push(Member::Group { name: name, required: required, members: read_members(c)? }),
+            other => return Err(format!("Unexpected <{}> in <{}>", other, el.name)),
+        }
+    }
+    Ok(members)
+}
+
+fn section<'a>(root: &'a Element, empty: &'a Element, name: &str) -> &'a Element
+{
+    root.child(name).unwrap_or(empty)
+}
+
+/// Parses QuickFIX XML dictionary
+pub fn parse<R: Read>(r: R) -> Result<Spec, String>
+{
+    let root = read_tree(r)?;
+    if root.name != "fix" {
+        return Err(format!("Unexpected root element <{}>", root.name));
+    }
+    let begin_string = format!("{}.{}.{}", root.attr("type").unwrap_or("FIX"),
+                               root.req_attr("major")?, root.req_attr("minor")?);
+
+    let empty = Element { name: String::new(), attributes: vec![], children: vec![] };
+
+    let mut messages = vec![];
+    for m in &section(&root, &empty, "messages").children {
+        messages.push(Message {
+            name: m.req_attr("name")?.to_string(),
+            msg_type: m.req_attr("msgtype")?.to_string(),
+            msg_cat: m.attr("msgcat").unwrap_or("app").to_string(),
+            members: read_members(m)?,
+        });
+    }
+
+    let mut components = vec![];
+    for c in &section(&root, &empty, "components").children {
+        components.push(Component {
+            name: c.req_attr("name")?.to_string(),
+            members: read_members(c)?,
+        });
+    }
+
+    let mut fields = vec![];
+    for f in &section(&root, &empty, "fields").children {
+        let number = f.req_attr("number")?;
+        fields.push(Field {
+            number: number.parse().map_err(|_| format!("Invalid field number {}", number))?,
+            name: f.req_attr("name")?.to_string(),
+            field_type: FieldType::from_str(f.req_attr("type")?),
+            values: f.children.iter()
+                .filter_map(|v| match (v.attr("enum"), v.attr("description")) {
+                    (Some(e), d) => Some((e.to_string(), d.unwrap_or(e).to_string())),
+                    _ => None,
+                })
+                .collect(),
+        });
+    }
+
+    Ok(Spec {
+        begin_string: begin_string,
+        header: read_members(section(&root, &empty, "header"))?,
+        trailer: read_members(section(&root, &empty, "trailer"))?,
+        messages: messages,
+        components: components,
+        fields: fields,
+    })
+}
+
+impl Spec {
+    pub fn field(&self, name: &str) -> Option<&Field>
+    {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    pub fn field_by_tag(&self, tag: u32) -> Option<&Field>
+    {
+        self.fields.iter().find(|f| f.number == tag)
+    }
+
+    pub fn tag(&self, name: &str) -> Result<u32, String>
+    {
+        self.field(name).map(|f| f.number).ok_or_else(|| format!("Unknown field {}", name))
+    }
+
+    pub fn component(&self, name: &str) -> Option<&Component>
+    {
+        self.components.iter().find(|c| c.name == name)
+    }
+
+    /// Replaces components with their members, recursively.
+    /// Members of an optional component become optional.
+    pub fn expand(&self, members: &[Member]) -> Result<Vec<Member>, String>
+    {
+        let mut res = vec![];
+        for m in members {
+            match *m {
+                Member::Component { ref name, required } => {
+                    let c = self.component(name).ok_or_else(|| format!("Unknown component {}", name))?;
+                    for cm in self.expand(&c.members)? {
+                        res.push(match cm {
+                            Member::Field { name, required: r } => Member::Field { name: name, required: r && required },
+                            Member::Group { name, required: r, members } => Member::Group { name: name, required: r && required, members: members },
+                            c => c,
+                        });
+                    }
+                },
+                Member::Group { ref name, required, ref members } => {
+                    res.push(Member::Group { name: name.clone(), required: required, members: self.expand(members)? });
+                },
+                ref f => res.push(f.clone()),
+            }
+        }
+        Ok(res)
+    }
+
+    /// All repeating groups found in header, trailer and messages (components expanded)
+    pub fn groups(&self) -> Result<Vec<Group>, String>
+    {
+        let mut groups: Vec<Group> = vec![];
+        let mut all = vec![self.expand(&self.header)?, self.expand(&self.trailer)?];
+        for m in &self.messages {
+            all.push(self.expand(&m.members)?);
+        }
+        for members in &all {
+            self.collect_groups(members, &mut groups)?;
+        }
+        Ok(groups)
+    }
+
+    fn collect_groups(&self, members: &[Member], to: &mut Vec<Group>) -> Result<(), String>
+    {
+        for m in members {
+            if let Member::Group { ref name, ref members, .. } = *m {
+                let no_tag = self.tag(name)?;
+                let mut tags = vec![];
+                for gm in members {
+                    match *gm {
+                        Member::Field { ref name, .. } | Member::Group { ref name, .. } => tags.push(self.tag(name)?),
+                        Member::Component { .. } => {},
+                    }
+                }
+                let delimiter = match tags.first() {
+                    Some(d) => *d,
+                    None => return Err(format!("Empty group {}", name)),
+                };
+                match to.iter().position(|g| g.no_tag == no_tag) {
+                    // same group used by different messages, merge members
+                    Some(pos) => {
+                        for t in tags {
+                            if !to[pos].members.contains(&t) {
+                                to[pos].members.push(t);
+                            }
+                        }
+                    },
+                    None => to.push(Group { no_tag: no_tag, delimiter: delimiter, members: tags }),
+                }
+                self.collect_groups(members, to)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+/*!
+  Session schedules: when the session is up and when a new session, with
+  sequence numbers starting over, begins.
+
+  Daily schedule runs from start to end time on every active day, end not
+  after start means the session ends the next day (i.e. 22:00 - 06:00).
+  Weekly schedule is one span from start day/time to end day/time, i.e.
+  Sunday 22:00 to Friday 22:00.
+
+  Times are local to the schedule's time zone. There is no tz database
+  around, TimeZone is the standard offset plus one of the common daylight
+  saving rules, TimeZone::named knows a handful of exchange locations.
+ */
+use fix::FixStreamException;
+use util::{civil_from_days, days_from_civil};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: i64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Weekday {
+    Sunday = 0,
+    Monday = 1,
+    Tuesday = 2,
+    Wednesday = 3,
+    Thursday = 4,
+    Friday = 5,
+    Saturday = 6,
+}
+
+pub const WEEKDAYS: &'static [Weekday] = &[
+    Weekday::Monday, Weekday::Tuesday, Weekday::Wednesday, Weekday::Thursday, Weekday::Friday,
+];
+
+/// When daylight saving time is in effect, one hour ahead of standard time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DstRule {
+    None,
+    /// last Sunday of March to last Sunday of October, 01:00 UTC
+    Eu,
+    /// second Sunday of March to first Sunday of November, 02:00 local time
+    Us,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeZone {
+    // standard offset, seconds east of UTC
+    offset: i64,
+    dst: DstRule,
+}
+
+/// (name, standard offset in minutes, DST)
+const ZONES: &'static [(&'static str, i64, DstRule)] = &[
+    ("UTC", 0, DstRule::None),
+    ("GMT", 0, DstRule::None),
+    ("Europe/London", 0, DstRule::Eu),
+    ("Europe/Dublin", 0, DstRule::Eu),
+    ("Europe/Lisbon", 0, DstRule::Eu),
+    ("Europe/Amsterdam", 60, DstRule::Eu),
+    ("Europe/Berlin", 60, DstRule::Eu),
+    ("Europe/Madrid", 60, DstRule::Eu),
+    ("Europe/Paris", 60, DstRule::Eu),
+    ("Europe/Rome", 60, DstRule::Eu),
+    ("Europe/Stockholm", 60, DstRule::Eu),
+    ("Europe/Zurich", 60, DstRule::Eu),
+    ("Europe/Athens", 120, DstRule::Eu),
+    ("Europe/Helsinki", 120, DstRule::Eu),
+    ("Europe/Moscow", 180, DstRule::None),
+    ("America/New_York", -300, DstRule::Us),
+    ("America/Chicago", -360, DstRule::Us),
+    ("America/Denver", -420, DstRule::Us),
+    ("America/Los_Angeles", -480, DstRule::Us),
+    ("America/Sao_Paulo", -180, DstRule::None),
+    ("Asia/Dubai", 240, DstRule::None),
+    ("Asia/Kolkata", 330, DstRule::None),
+    ("Asia/Singapore", 480, DstRule::None),
+    ("Asia/Hong_Kong", 480, DstRule::None),
+    ("Asia/Shanghai", 480, DstRule::None),
+    ("Asia/Tokyo", 540, DstRule::None),
+];
+
+impl TimeZone {
+    pub fn utc() -> TimeZone
+    {
+        TimeZone::fixed(0)
+    }
+
+    /// Constant offset from UTC in seconds, east is positive
+    pub fn fixed(offset: i32) -> TimeZone
+    {
+        TimeZone::with_dst(offset, DstRule::None)
+    }
+
+    pub fn with_dst(offset: i32, dst: DstRule) -> TimeZone
+    {
+        TimeZone { offset: offset as i64, dst: dst }
+    }
+
+    /// Zone by its tz database name, None if it is not one of the known ones
+    pub fn named(name: &str) -> Option<TimeZone>
+    {
+        ZONES.iter()
+            .find(|&&(n, _, _)| n == name)
+            .map(|&(_, minutes, dst)| TimeZone { offset: minutes * 60, dst: dst })
+    }
+
+    /// Offset from UTC in effect at `t` (seconds since epoch)
+    fn offset_at(&self, t: i64) -> i64
+    {
+        let (year, _, _) = civil_from_days(div_floor(t, SECS_PER_DAY));
+        let (from, to) = match self.dst {
+            DstRule::None => return self.offset,
+            DstRule::Eu => {
+                let last_sunday = |month| {
+                    let last = days_from_civil(year, month, 31);
+                    last - weekday(last) as i64
+                };
+                (last_sunday(3) * SECS_PER_DAY + 3600, last_sunday(10) * SECS_PER_DAY + 3600)
+            },
+            DstRule::Us => {
+                let first_sunday = |month| {
+                    let first = days_from_civil(year, month, 1);
+                    first + (7 - weekday(first) as i64) % 7
+                };
+                (((first_sunday(3) + 7) * SECS_PER_DAY) + 7200 - self.offset,
+                 (first_sunday(11) * SECS_PER_DAY) + 7200 - self.offset - 3600)
+            },
+        };
+        if t >= from && t < to { self.offset + 3600 } else { self.offset }
+    }
+
+    /// UTC time of the local `t`, local time skipped or repeated by
+    /// DST switch resolves to either side of it
+    fn to_utc(&self, local: i64) -> i64
+    {
+        local - self.offset_at(local - self.offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Span {
+    /// bit per active Weekday
+    Daily(u8),
+    Weekly(Weekday, Weekday),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSchedule {
+    // seconds since local midnight
+    start: i64,
+    end: i64,
+    span: Span,
+    time_zone: TimeZone,
+}
+
+impl SessionSchedule {
+    /// Session every day from `start` to `end` ("HH:MM" or "HH:MM:SS"), UTC
+    pub fn daily(start: &str, end: &str) -> Result<SessionSchedule, FixStreamException>
+    {
+        Ok(SessionSchedule {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            span: Span::Daily(0x7f),
+            time_zone: TimeZone::utc(),
+        })
+    }
+
+    /// One session a week from `start` on `start_day` to `end` on `end_day`, UTC
+    pub fn weekly(start_day: Weekday, start: &str, end_day: Weekday, end: &str)
+        -> Result<SessionSchedule, FixStreamException>
+    {
+        Ok(SessionSchedule {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            span: Span::Weekly(start_day, end_day),
+            time_zone: TimeZone::utc(),
+        })
+    }
+
+    /// Days daily session starts on, weekly schedule is not affected
+    pub fn with_days(mut self, days: &[Weekday]) -> SessionSchedule
+    {
+        if let Span::Daily(_) = self.span {
+            self.span = Span::Daily(days.iter().fold(0, |mask, &d| mask | 1 << d as u8));
+        }
+        self
+    }
+
+    pub fn with_time_zone(mut self, time_zone: TimeZone) -> SessionSchedule
+    {
+        self.time_zone = time_zone;
+        self
+    }
+
+    pub fn is_active(&self, t: SystemTime) -> bool
+    {
+        self.period(t).is_some()
+    }
+
+    /// (start, end) of the session `t` falls in
+    pub fn period(&self, t: SystemTime) -> Option<(SystemTime, SystemTime)>
+    {
+        let t = secs(t);
+        self.periods_around(t)
+            .into_iter()
+            .find(|&(start, end)| start <= t && t < end)
+            .map(|(start, end)| (time(start), time(end)))
+    }
+
+    /// When the session next starts or ends after `t`, None if it never starts
+    pub fn next_change(&self, t: SystemTime) -> Option<SystemTime>
+    {
+        let t = secs(t);
+        let periods = self.periods_around(t);
+        if let Some(&(_, end)) = periods.iter().find(|&&(start, end)| start <= t && t < end) {
+            return Some(time(end));
+        }
+        periods.iter()
+            .map(|&(start, _)| start)
+            .filter(|&start| start > t)
+            .min()
+            .map(time)
+    }
+
+    /// Sessions starting up to a week before or after `t`
+    fn periods_around(&self, t: i64) -> Vec<(i64, i64)>
+    {
+        let today = div_floor(t + self.time_zone.offset_at(t), SECS_PER_DAY);
+        let (days, ends_after) = match self.span {
+            Span::Daily(mask) => {
+                (mask, if self.end > self.start { 0 } else { 1 })
+            },
+            Span::Weekly(start_day, end_day) => {
+                let days = (end_day as i64 - start_day as i64 + 7) % 7;
+                (1 << start_day as u8, if days == 0 && self.end <= self.start { 7 } else { days })
+            },
+        };
+
+        let mut periods = vec![];
+        for day in today - 8..today + 9 {
+            if days & 1 << weekday(day) != 0 {
+                let start = self.time_zone.to_utc(day * SECS_PER_DAY + self.start);
+                let end = self.time_zone.to_utc((day + ends_after) * SECS_PER_DAY + self.end);
+                periods.push((start, end));
+            }
+        }
+        periods
+    }
+}
+
+fn parse_time(s: &str) -> Result<i64, FixStreamException>
+{
+    let parts: Vec<Option<i64>> = s.split(':').map(|p| p.trim().parse().ok()).collect();
+    let hms = match parts.len() {
+        2 => (parts[0], parts[1], Some(0)),
+        3 => (parts[0], parts[1], parts[2]),
+        _ => (None, None, None),
+    };
+    match hms {
+        (Some(h), Some(m), Some(sec)) if h >= 0 && h < 24 && m >= 0 && m < 60 && sec >= 0 && sec < 60 => {
+            Ok(h * 3600 + m * 60 + sec)
+        },
+        _ => Err(format!("Malformed time of day: {}", s)),
+    }
+}
+
+fn secs(t: SystemTime) -> i64
+{
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+fn time(secs: i64) -> SystemTime
+{
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+}
+
+fn div_floor(a: i64, b: i64) -> i64
+{
+    (a - ((a % b) + b) % b) / b
+}
+
+/// 0 is Sunday, day 0 (1970-01-01) was Thursday
+fn weekday(days: i64) -> u32
+{
+    ((days % 7 + 11) % 7) as u32
+}
@@ -0,0 +1,194 @@
+/*!
+  FIX session lifecycle as a state machine over (FixInState, FixOutState).
+
+  Every event is looked up in the transition tables of both sides and has
+  to be known to both of them in the current state, otherwise it's an
+  illegal transition. Events that leave one side as is have explicit
+  unchanged entries in that side's table.
+
+  Out side: Logon -> Connected -> Lagging (ResendRequest sent, waiting for
+  the counterparty to fill the gap) or Resending (replaying our messages)
+  -> Connected -> Logout -> Disconnected. Resending may start while Lagging,
+  once it's done the out side goes back to the state it was resending from.
+ */
+use std::collections::VecDeque;
+use fix::*;
+
+/// Events driving the session state machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionEvent {
+    /// Transport connected (initiator) or accepted (acceptor)
+    Connected,
+    /// Logon exchange completed
+    LogonAccepted,
+    /// Incoming sequence gap detected, ResendRequest sent
+    GapDetected,
+    /// Incoming gap closed
+    GapFilled,
+    /// Started replaying messages for counterparty's ResendRequest
+    ResendStarted,
+    ResendDone,
+    LogoutSent,
+    LogoutReceived,
+    /// Transport closed, always allowed
+    Disconnected,
+}
+
+/// Recorded state change
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionTransition {
+    pub event: SessionEvent,
+    pub from: (FixInState, FixOutState),
+    pub to: (FixInState, FixOutState),
+}
+
+/// Number of transitions kept in the event log
+pub const LOG_CAPACITY: usize = 1024;
+
+/// (from, event, to), None matches any state
+const IN_TRANSITIONS: &'static [(Option<FixInState>, SessionEvent, FixInState)] = &[
+    (Some(FixInState::Disconnected), SessionEvent::Connected, FixInState::Logon),
+    (Some(FixInState::Logon), SessionEvent::LogonAccepted, FixInState::Connected),
+    (Some(FixInState::Logon), SessionEvent::LogoutSent, FixInState::Logout),
+    (Some(FixInState::Connected), SessionEvent::LogoutSent, FixInState::Logout),
+    (Some(FixInState::Logon), SessionEvent::LogoutReceived, FixInState::Logout),
+    (Some(FixInState::Connected), SessionEvent::LogoutReceived, FixInState::Logout),
+    (Some(FixInState::Logout), SessionEvent::LogoutReceived, FixInState::Logout),
+    // reply to counterparty's Logout
+    (Some(FixInState::Logout), SessionEvent::LogoutSent, FixInState::Logout),
+    // gaps and resends are tracked by the out side only
+    (Some(FixInState::Connected), SessionEvent::GapDetected, FixInState::Connected),
+    (Some(FixInState::Connected), SessionEvent::GapFilled, FixInState::Connected),
+    (Some(FixInState::Connected), SessionEvent::ResendStarted, FixInState::Connected),
+    (Some(FixInState::Connected), SessionEvent::ResendDone, FixInState::Connected),
+    // counterparty may ask for a resend before it answers our Logout
+    (Some(FixInState::Logout), SessionEvent::ResendStarted, FixInState::Logout),
+    (Some(FixInState::Logout), SessionEvent::ResendDone, FixInState::Logout),
+    (None, SessionEvent::Disconnected, FixInState::Disconnected),
+];
+
+const OUT_TRANSITIONS: &'static [(Option<FixOutState>, SessionEvent, FixOutState)] = &[
+    (Some(FixOutState::Disconnected), SessionEvent::Connected, FixOutState::Logon),
+    (Some(FixOutState::Logon), SessionEvent::LogonAccepted, FixOutState::Connected),
+    (Some(FixOutState::Connected), SessionEvent::GapDetected, FixOutState::Lagging),
+    (Some(FixOutState::Lagging), SessionEvent::GapDetected, FixOutState::Lagging),
+    (Some(FixOutState::Lagging), SessionEvent::GapFilled, FixOutState::Connected),
+    (Some(FixOutState::Connected), SessionEvent::ResendStarted, FixOutState::Resending),
+    (Some(FixOutState::Lagging), SessionEvent::ResendStarted, FixOutState::Resending),
+    (Some(FixOutState::Resending), SessionEvent::ResendDone, FixOutState::Connected),
+    (Some(FixOutState::Logon), SessionEvent::LogoutSent, FixOutState::Logout),
+    (Some(FixOutState::Connected), SessionEvent::LogoutSent, FixOutState::Logout),
+    (Some(FixOutState::Lagging), SessionEvent::LogoutSent, FixOutState::Logout),
+    (Some(FixOutState::Resending), SessionEvent::LogoutSent, FixOutState::Logout),
+    (Some(FixOutState::Logon), SessionEvent::LogoutReceived, FixOutState::Logout),
+    (Some(FixOutState::Connected), SessionEvent::LogoutReceived, FixOutState::Logout),
+    (Some(FixOutState::Lagging), SessionEvent::LogoutReceived, FixOutState::Logout),
+    (Some(FixOutState::Resending), SessionEvent::LogoutReceived, FixOutState::Logout),
+    (Some(FixOutState::Logout), SessionEvent::LogoutReceived, FixOutState::Logout),
+    // reply to counterparty's Logout
+    (Some(FixOutState::Logout), SessionEvent::LogoutSent, FixOutState::Logout),
+    (Some(FixOutState::Logout), SessionEvent::ResendStarted, FixOutState::Logout),
+    (Some(FixOutState::Logout), SessionEvent::ResendDone, FixOutState::Logout),
+    (None, SessionEvent::Disconnected, FixOutState::Disconnected),
+];
+
+fn lookup<S>(table: &[(Option<S>, SessionEvent, S)], from: S, event: SessionEvent) -> Option<S>
+    where S: Copy + PartialEq
+{
+    table.iter()
+        .find(|&&(f, e, _)| e == event && (f.is_none() || f == Some(from)))
+        .map(|&(_, _, to)| to)
+}
+
+#[derive(Debug)]
+pub struct SessionState {
+    in_state: FixInState,
+    out_state: FixOutState,
+    // out state ResendDone goes back to
+    resend_from: Option<FixOutState>,
+    log: VecDeque<SessionTransition>,
+}
+
+impl SessionState {
+    pub fn new() -> SessionState
+    {
+        SessionState {
+            in_state: FixInState::Disconnected,
+            out_state: FixOutState::Disconnected,
+            resend_from: None,
+            log: VecDeque::with_capacity(LOG_CAPACITY),
+        }
+    }
+
+    pub fn in_state(&self) -> FixInState
+    {
+        self.in_state
+    }
+
+    pub fn out_state(&self) -> FixOutState
+    {
+        self.out_state
+    }
+
+    /// Logon completed on both sides and logout not started
+    pub fn is_logged_on(&self) -> bool
+    {
+        match (self.in_state, self.out_state) {
+            (FixInState::Connected, FixOutState::Connected) |
+            (FixInState::Connected, FixOutState::Lagging) |
+            (FixInState::Connected, FixOutState::Resending) => true,
+            _ => false,
+        }
+    }
+
+    /// Moves both sides according to the transition tables,
+    /// state is left intact if the event is illegal in the current state
+    pub fn apply(&mut self, event: SessionEvent) -> Result<(), FixStreamException>
+    {
+        let (in_to, out_to) = match (lookup(IN_TRANSITIONS, self.in_state, event),
+                                     lookup(OUT_TRANSITIONS, self.out_state, event)) {
+            (Some(in_to), Some(out_to)) => (in_to, out_to),
+            _ => return Err(format!("Illegal session transition: {:?} in {:?}/{:?}",
+                                    event, self.in_state, self.out_state)),
+        };
+
+        let out_to = match event {
+            SessionEvent::ResendStarted => {
+                self.resend_from = Some(self.out_state);
+                out_to
+            },
+            SessionEvent::ResendDone => self.resend_from.take().unwrap_or(out_to),
+            SessionEvent::Disconnected => {
+                self.resend_from = None;
+                out_to
+            },
+            _ => out_to,
+        };
+
+        let from = (self.in_state, self.out_state);
+        self.in_state = in_to;
+        self.out_state = out_to;
+
+        if self.log.len() == LOG_CAPACITY {
+            self.log.pop_front();
+        }
+        self.log.push_back(SessionTransition {
+            event: event,
+            from: from,
+            to: (self.in_state, self.out_state),
+        });
+        Ok(())
+    }
+
+    /// Transitions made so far (up to LOG_CAPACITY latest)
+    pub fn log(&self) -> &VecDeque<SessionTransition>
+    {
+        &self.log
+    }
+
+    /// Drains the log, i.e. for periodic monitoring
+    pub fn take_log(&mut self) -> Vec<SessionTransition>
+    {
+        self.log.drain(..).collect()
+    }
+}
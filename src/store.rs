@@ -0,0 +1,322 @@
+/*!
+  Session persistence: sequence numbers of both sides, session creation
+  time and sent messages, used to service ResendRequest and to carry on
+  after restart.
+
+  Only messages that may be re-sent are stored (application messages
+  written with is_replayable), every number missing from the store is
+  replaced by SequenceReset-GapFill on resend.
+
+  FileStore keeps the session in four files next to each other:
+  <name>.body - sent messages back to back, <name>.header - "seq,offset,len"
+  line per message, <name>.seqnums - "sender : target" and <name>.session -
+  creation time (seconds.nanoseconds since epoch).
+ */
+use fix::FixStreamException;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait FixMessageStore {
+    /// MsgSeqNum of the next message we send
+    fn next_sender_seq(&self) -> u32;
+    fn set_next_sender_seq(&mut self, seq: u32) -> Result<(), FixStreamException>;
+    /// MsgSeqNum expected from the counterparty
+    fn next_target_seq(&self) -> u32;
+    fn set_next_target_seq(&mut self, seq: u32) -> Result<(), FixStreamException>;
+    fn creation_time(&self) -> SystemTime;
+    /// Stores complete message as it was sent
+    fn set(&mut self, seq: u32, msg: &[u8]) -> Result<(), FixStreamException>;
+    fn get(&self, seq: u32) -> Option<Vec<u8>>;
+    /// New session: both numbers back to 1, messages are forgotten
+    fn reset(&mut self) -> Result<(), FixStreamException>;
+}
+
+/// Sent messages kept in memory, indexed by MsgSeqNum
+pub struct MemoryStore {
+    messages: BTreeMap<u32, Vec<u8>>,
+    next_sender: u32,
+    next_target: u32,
+    created: SystemTime,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore
+    {
+        MemoryStore {
+            messages: BTreeMap::new(),
+            next_sender: 1,
+            next_target: 1,
+            created: SystemTime::now(),
+        }
+    }
+}
+
+impl FixMessageStore for MemoryStore {
+    fn next_sender_seq(&self) -> u32
+    {
+        self.next_sender
+    }
+
+    fn set_next_sender_seq(&mut self, seq: u32) -> Result<(), FixStreamException>
+    {
+        self.next_sender = seq;
+        Ok(())
+    }
+
+    fn next_target_seq(&self) -> u32
+    {
+        self.next_target
+    }
+
+    fn set_next_target_seq(&mut self, seq: u32) -> Result<(), FixStreamException>
+    {
+        self.next_target = seq;
+        Ok(())
+    }
+
+    fn creation_time(&self) -> SystemTime
+    {
+        self.created
+    }
+
+    fn set(&mut self, seq: u32, msg: &[u8]) -> Result<(), FixStreamException>
+    {
+        self.messages.insert(seq, msg.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, seq: u32) -> Option<Vec<u8>>
+    {
+        self.messages.get(&seq).cloned()
+    }
+
+    fn reset(&mut self) -> Result<(), FixStreamException>
+    {
+        *self = MemoryStore::new();
+        Ok(())
+    }
+}
+
+/// Store surviving restarts, messages are read back from disk on resend
+pub struct FileStore {
+    dir: PathBuf,
+    name: String,
+    body: File,
+    header: File,
+    // seq -> (offset, len) in body
+    index: BTreeMap<u32, (u64, usize)>,
+    body_len: u64,
+    next_sender: u32,
+    next_target: u32,
+    created: SystemTime,
+}
+
+impl FileStore {
+    /// Opens the store of session `name` in `dir`, new session is created if there is none
+    pub fn open<P: AsRef<Path>>(dir: P, name: &str) -> Result<FileStore, FixStreamException>
+    {
+        let (body, header) = FileStore::open_files(dir.as_ref(), name)?;
+        let mut store = FileStore {
+            dir: dir.as_ref().to_path_buf(),
+            name: name.to_string(),
+            body: body,
+            header: header,
+            index: BTreeMap::new(),
+            body_len: 0,
+            next_sender: 1,
+            next_target: 1,
+            created: SystemTime::now(),
+        };
+        store.body_len = store.body.metadata().map_err(|e| io_error(&store.file("body"), e))?.len();
+        store.load()?;
+        Ok(store)
+    }
+
+    fn file(&self, ext: &str) -> PathBuf
+    {
+        self.dir.join(format!("{}.{}", self.name, ext))
+    }
+
+    fn open_files(dir: &Path, name: &str) -> Result<(File, File), FixStreamException>
+    {
+        let open = |ext: &str| {
+            let p = dir.join(format!("{}.{}", name, ext));
+            OpenOptions::new().read(true).append(true).create(true).open(&p).map_err(|e| io_error(&p, e))
+        };
+        Ok((open("body")?, open("header")?))
+    }
+
+    fn load(&mut self) -> Result<(), FixStreamException>
+    {
+        let mut header = String::new();
+        (&self.header).read_to_string(&mut header).map_err(|e| io_error(&self.file("header"), e))?;
+        // line without trailing newline is torn even if what's left of it parses,
+        // it's cut off so that the next entry starts on a line of its own
+        let complete = header.rfind('\n').map_or(0, |pos| pos + 1);
+        if complete < header.len() {
+            self.header.set_len(complete as u64).map_err(|e| io_error(&self.file("header"), e))?;
+        }
+        for line in header[..complete].lines() {
+            let parts: Vec<&str> = line.split(',').collect();
+            if parts.len() == 3 {
+                let entry = (parts[0].parse(), parts[1].parse::<u64>(), parts[2].parse::<usize>());
+                if let (Ok(seq), Ok(offset), Ok(len)) = entry {
+                    // message not completely written is dropped
+                    if offset + len as u64 <= self.body_len {
+                        self.index.insert(seq, (offset, len));
+                    }
+                }
+            }
+        }
+
+        match read_file(&self.file("seqnums"))? {
+            Some(seqnums) => {
+                let parts: Vec<&str> = seqnums.split(':').map(|s| s.trim()).collect();
+                match (parts.get(0).and_then(|s| s.parse().ok()), parts.get(1).and_then(|s| s.parse().ok())) {
+                    (Some(sender), Some(target)) => {
+                        self.next_sender = sender;
+                        self.next_target = target;
+                    },
+                    _ => return Err(format!("Malformed seqnums: {}", seqnums)),
+                }
+            },
+            None => self.save_seqnums()?,
+        }
+        // stored message is written before the numbers, crash in between must not reuse it
+        if let Some((&last, _)) = self.index.iter().next_back() {
+            if last >= self.next_sender {
+                self.next_sender = last + 1;
+                self.save_seqnums()?;
+            }
+        }
+
+        match read_file(&self.file("session"))? {
+            Some(session) => {
+                let mut parts = session.trim().split('.');
+                let secs = parts.next().and_then(|s| s.parse().ok());
+                let nanos = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+                match secs {
+                    Some(secs) => self.created = UNIX_EPOCH + Duration::new(secs, nanos),
+                    None => return Err(format!("Malformed session time: {}", session)),
+                }
+            },
+            None => self.save_creation_time()?,
+        }
+        Ok(())
+    }
+
+    fn save_seqnums(&self) -> Result<(), FixStreamException>
+    {
+        write_file(&self.file("seqnums"), &format!("{:010} : {:010}", self.next_sender, self.next_target))
+    }
+
+    fn save_creation_time(&self) -> Result<(), FixStreamException>
+    {
+        let since = self.created.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        write_file(&self.file("session"), &format!("{}.{:09}", since.as_secs(), since.subsec_nanos()))
+    }
+}
+
+impl FixMessageStore for FileStore {
+    fn next_sender_seq(&self) -> u32
+    {
+        self.next_sender
+    }
+
+    fn set_next_sender_seq(&mut self, seq: u32) -> Result<(), FixStreamException>
+    {
+        self.next_sender = seq;
+        self.save_seqnums()
+    }
+
+    fn next_target_seq(&self) -> u32
+    {
+        self.next_target
+    }
+
+    fn set_next_target_seq(&mut self, seq: u32) -> Result<(), FixStreamException>
+    {
+        self.next_target = seq;
+        self.save_seqnums()
+    }
+
+    fn creation_time(&self) -> SystemTime
+    {
+        self.created
+    }
+
+    fn set(&mut self, seq: u32, msg: &[u8]) -> Result<(), FixStreamException>
+    {
+        // body goes first, index entry pointing past the end is never trusted
+        self.body.write_all(msg).map_err(|e| io_error(&self.file("body"), e))?;
+        let entry = format!("{},{},{}\n", seq, self.body_len, msg.len());
+        self.header.write_all(entry.as_bytes()).map_err(|e| io_error(&self.file("header"), e))?;
+        self.index.insert(seq, (self.body_len, msg.len()));
+        self.body_len += msg.len() as u64;
+        Ok(())
+    }
+
+    fn get(&self, seq: u32) -> Option<Vec<u8>>
+    {
+        let (offset, len) = match self.index.get(&seq) {
+            Some(&entry) => entry,
+            None => return None,
+        };
+        let mut body = &self.body;
+        let mut msg = vec![0; len];
+        body.seek(SeekFrom::Start(offset))
+            .and_then(|_| body.read_exact(&mut msg))
+            .ok()
+            .map(|_| msg)
+    }
+
+    fn reset(&mut self) -> Result<(), FixStreamException>
+    {
+        for ext in &["body", "header"] {
+            File::create(self.file(ext)).map_err(|e| io_error(&self.file(ext), e))?;
+        }
+        let (body, header) = FileStore::open_files(&self.dir, &self.name)?;
+        self.body = body;
+        self.header = header;
+        self.index.clear();
+        self.body_len = 0;
+        self.next_sender = 1;
+        self.next_target = 1;
+        self.created = SystemTime::now();
+        self.save_seqnums()?;
+        self.save_creation_time()
+    }
+}
+
+fn io_error(path: &Path, e: ::std::io::Error) -> FixStreamException
+{
+    format!("{}: {}", path.display(), e)
+}
+
+fn read_file(path: &Path) -> Result<Option<String>, FixStreamException>
+{
+    let mut s = String::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_string(&mut s).map_err(|e| io_error(path, e))?;
+            Ok(Some(s))
+        },
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io_error(path, e)),
+    }
+}
+
+/// Replaces the file as a whole, crash leaves either old or new content
+fn write_file(path: &Path, content: &str) -> Result<(), FixStreamException>
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(content.as_bytes()).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| io_error(path, e))
+}
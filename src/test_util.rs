@@ -3,9 +3,11 @@ use std::result::Result;
 use std::fmt::format;
 use connection::FixConnection;
 use connection::ConnectionType;
+use store::FixMessageStore;
+use dictionary::ValidationError;
 use super::util;
 use std::collections::HashMap;
-use std::cell::{RefCell, UnsafeCell};
+use std::cell::{Cell, RefCell, UnsafeCell};
 use std::str;
 use std::string::String;
 use std::time::{Duration, SystemTime};
@@ -21,14 +23,28 @@ pub struct TestFixApplication
 {
     pub message: TestFixMessage,
     pub requests: Vec<SessionRequest>,
+    /// complete every request right away (with request_done)
+    pub auto_done: bool,
+    /// decline incoming logon with given reason
+    pub reject_logon: Option<LogoutFields>,
+    /// session level rejects with the validation errors behind them
+    pub rejects: Vec<(RejectFields, ValidationError)>,
+    /// connection events in order
+    pub events: Vec<ConnectionEvent>,
 }
 
 pub struct TestFixEnvironment
 {
-	now: SystemTime,
+	now: Rc<Cell<SystemTime>>,
 	timers: List<TestFixTimerDesc>,
 }
 
+/// Clock following the environment's time
+pub struct TestFixClock
+{
+	now: Rc<Cell<SystemTime>>,
+}
+
 pub struct TestFixTimerDesc
 {
 	d: Duration,
@@ -51,6 +67,15 @@ pub struct TestFixRemote {
 	len: usize,
 	lenOff: usize,
 	data: UnsafeCell<Vec<u8>>,
+	/// MsgSeqNum of the next message sent to the connection
+	pub seq: u32,
+	/// bytes written by the connection
+	pub sent: Vec<u8>,
+	pub disconnected: bool,
+	/// number of connect attempts to fail from now on
+	pub refuse_connects: u32,
+	/// endpoints set by the connection, in order
+	pub endpoints: Vec<String>,
     pend_accept: Vec<Box<FnOnce() -> ()>>,
     pend_read: Vec<Box<FnOnce() -> ()>>,
 }
@@ -69,25 +94,42 @@ impl FixAppMsgType for () {
     {
         None
     }
+
+    fn as_bytes(&self) -> &[u8]
+    {
+        b""
+    }
 }
 
 impl TestFixEnvironment
 {
 	pub fn new() -> TestFixEnvironment {
 		TestFixEnvironment {
-			now: SystemTime::now(),
+			now: Rc::new(Cell::new(SystemTime::now())),
 			timers: List::new(),
 		}
 	}
 
+	/// Moves the time to `t` without firing timers, meant to be called before any timer is set
+	pub fn set_now(&mut self, t: SystemTime)
+	{
+		self.now.set(t);
+	}
+
+	pub fn clock(&self) -> TestFixClock
+	{
+		TestFixClock { now: self.now.clone() }
+	}
+
+	/// Advances time firing every timer that is due, in order of the timers set
 	pub fn run_for(&mut self, d: Duration)
 	{
+		let now = self.now.get() + d;
+		self.now.set(now);
 		for th in self.timers.iter_mut() {
-			self.now += d;
-			let num = (d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64) / (th.d.as_secs() * 1_000_000_000 + th.d.subsec_nanos() as u64);
-			for _ in 0..num {
-				(th.on_timeout)();
+			while th.last + th.d <= now {
 				th.last += th.d;
+				(th.on_timeout)();
 			}
 		}
 	}
@@ -139,6 +181,11 @@ impl TestFixRemote {
 			len: 0,
 			lenOff: 0,
 			data: UnsafeCell::new(vec![0u8;0]),
+			seq: 1,
+			sent: vec![],
+			disconnected: false,
+			refuse_connects: 0,
+			endpoints: vec![],
             pend_read: vec![],
             pend_accept: vec![],
 		}
@@ -148,21 +195,79 @@ impl TestFixRemote {
     {
         self.pend_accept.pop().unwrap()();
     }
+
+    /// Parses and drains messages written by the connection so far
+    pub fn sent_messages(&mut self) -> Vec<TestFixMessage>
+    {
+        let mut msgs = vec![];
+        loop {
+            let mut msg = TestFixMessage::new();
+            match util::parse_fix_message(&self.sent, &mut msg) {
+                Ok(Some(len)) => {
+                    self.sent.drain(0..len);
+                    msgs.push(msg);
+                },
+                _ => return msgs,
+            }
+        }
+    }
+
+    /// Counterparty's Logon, HeartBtInt(108)=30
+    pub fn logon(&mut self)
+    {
+        self.fix_message_start(FixMsgType::Logon, false);
+        self.tag_value(108, b"30");
+        self.fix_message_done(Ok(()));
+    }
+}
+
+pub type TestFixConnection = FixConnection<TestFixTransport, TestFixEnvironment>;
+
+/// Connection not connected yet, the application completes every request right away
+pub fn connection(conn_type: ConnectionType, store: Box<FixMessageStore>)
+    -> (Rc<RefCell<TestFixRemote>>, TestFixConnection, TestFixApplication)
+{
+    let r = Rc::new(RefCell::new(TestFixRemote::new()));
+    let (tft, env, mut fix_app) = fix_parts(r.clone());
+    fix_app.auto_done = true;
+    let fc = FixConnection::new(String::from("FIX.4.2"), tft, env, conn_type, store);
+    (r, fc, fix_app)
+}
+
+/// Connects and exchanges Logon, the counterparty carries on from the stored sequence.
+/// Messages sent so far are drained.
+pub fn log_on(r: &Rc<RefCell<TestFixRemote>>, fc: &mut TestFixConnection, fix_app: &mut TestFixApplication)
+{
+    r.borrow_mut().seq = fc.get_expected_incoming_seq();
+    fc.connect(fix_app);
+    r.borrow_mut().logon();
+    fc.read_fix_message(fix_app);
+    r.borrow_mut().sent_messages();
+}
+
+/// Connection of `conn_type` over `store` with logon completed
+pub fn logged_on(conn_type: ConnectionType, store: Box<FixMessageStore>)
+    -> (Rc<RefCell<TestFixRemote>>, TestFixConnection, TestFixApplication)
+{
+    let (r, mut fc, mut fix_app) = connection(conn_type, store);
+    log_on(&r, &mut fc, &mut fix_app);
+    (r, fc, fix_app)
 }
 
 pub fn fix_parts(remote: Rc<RefCell<TestFixRemote>>) -> (TestFixTransport, TestFixEnvironment, TestFixApplication)
 {
     let tft = TestFixTransport { remote: remote, };
     let env = TestFixEnvironment::new();
-    let fix_app = TestFixApplication { requests: vec![], message: TestFixMessage::new() };
+    let fix_app = TestFixApplication { requests: vec![], message: TestFixMessage::new(), auto_done: false, reject_logon: None, rejects: vec![], events: vec![] };
     (tft, env, fix_app)
 }
 
 impl<T> FixTimerHandler for TestFixTimerHandler<T>
 where T: ListHandle<TestFixTimerDesc>
 {
-	fn cancel(self) {
-		self.timerh.unlink();
+	fn cancel(self: Box<Self>) {
+		let this = *self;
+		this.timerh.unlink();
 	}
 }
 
@@ -173,7 +278,7 @@ impl FixTimerFactory for TestFixEnvironment
 	{
 		let mut desc = TestFixTimerDesc {
 			d: duration,
-			last: self.now,
+			last: self.now.get(),
 			on_timeout: Box::new(on_timeout),
 		};
 
@@ -185,13 +290,30 @@ impl FixTimerFactory for TestFixEnvironment
 	}
 }
 
+impl FixClock for TestFixClock
+{
+	fn now(&self) -> SystemTime {
+		self.now.get()
+	}
+}
+
 impl FixTransport for TestFixTransport
 {
 	fn connect<F>(&mut self, mut on_success: F) where F: FnOnce(&mut Self) -> ()
 	{
-       //self.remote.borrow_mut().pend_accept.push(box move || {
-       //    on_success(self);
-       //});
+		{
+			let mut remote = self.remote.borrow_mut();
+			if remote.refuse_connects > 0 {
+				remote.refuse_connects -= 1;
+				return;
+			}
+			remote.disconnected = false;
+		}
+		on_success(self);
+	}
+
+	fn set_endpoint(&mut self, endpoint: &str) {
+		self.remote.borrow_mut().endpoints.push(endpoint.to_string());
 	}
 
 	fn view(&self) -> &[u8] {
@@ -203,8 +325,13 @@ impl FixTransport for TestFixTransport
 	}
 
     fn write(&mut self, buf: &[u8]) -> usize {
+        self.remote.borrow_mut().sent.extend_from_slice(buf);
         buf.len()
     }
+
+	fn disconnect(&mut self) {
+		self.remote.borrow_mut().disconnected = true;
+	}
 	
     fn on_read<F>(&mut self, mut on_success: F) where F: FnOnce(&mut Self) -> ()
 	{
@@ -219,6 +346,13 @@ impl FixApplication for TestFixApplication
     fn on_request<S>(&mut self, r: SessionRequest, svs: &mut S)
     where S: FixService
     {
+        match (&r, self.reject_logon.clone()) {
+            (&SessionRequest::In(SessionRequestType::Logon(_)), Some(reason)) => {
+                svs.request_rejected(r.clone(), reason);
+            },
+            _ if self.auto_done => svs.request_done(r.clone()),
+            _ => {},
+        }
         self.requests.push(r);
     }
 
@@ -232,6 +366,16 @@ impl FixApplication for TestFixApplication
     {
         &mut self.message
     }
+
+    fn on_session_reject(&mut self, reject: &RejectFields, error: &ValidationError)
+    {
+        self.rejects.push((reject.clone(), error.clone()));
+    }
+
+    fn on_connection_event(&mut self, event: &ConnectionEvent)
+    {
+        self.events.push(event.clone());
+    }
 }
 
 impl FixStream for TestFixRemote
@@ -254,6 +398,10 @@ impl FixStream for TestFixRemote
 		// message type
 		let v = msg_type.as_bytes();
 		self.tag_value(35, &v);
+
+		let seq = self.seq.to_string();
+		self.tag_value(34, seq.as_bytes());
+		self.seq += 1;
 	}
 
 	fn fix_message_done(&mut self, res: Result<(), FixStreamException>)
@@ -4,9 +4,12 @@ use fix::FixStream;
 use fix::FixTagHandler;
 use fix::FixStreamException;
 use fix::FixParseIdLenSum;
+use fix::FixDictionary;
 use std::result::Result;
+use std::rc::Rc;
 use std::fmt::format;
 use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use fix_tags::{header, body, trailer};
 
 const ASCII_ZERO: i32 = ('0' as i32);
@@ -140,6 +143,20 @@ pub fn get_tag_id(buf: &[u8]) -> Result<Option<FixParseIdLenSum>, FixStreamExcep
     return Ok(None);
 }
 
+/// Parses unsigned decimal, None if empty or contains anything but digits
+pub fn parse_u32(v: &[u8]) -> Option<u32>
+{
+    if v.is_empty() {
+        return None;
+    }
+    v.iter().fold(Some(0u32), |acc, &x| {
+        match (acc, x) {
+            (Some(acc), b'0'...b'9') => acc.checked_mul(10).and_then(|acc| acc.checked_add((x - b'0') as u32)),
+            _ => None,
+        }
+    })
+}
+
 pub fn put_tag_id_eq(tag_id: u32, to: &mut Vec<u8>) -> (u32, usize)
 {
     // todo: think how to speed this up
@@ -188,12 +205,84 @@ pub fn put_u32_soh(val: u32, to: &mut Vec<u8>) -> (u32, usize)
     (sum, len)
 }
 
+/// UTCTimestamp value (YYYYMMDD-HH:MM:SS.sss)
+pub fn utc_timestamp(t: SystemTime) -> String
+{
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let secs = d.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let sod = secs % 86400;
+    format!("{:04}{:02}{:02}-{:02}:{:02}:{:02}.{:03}",
+            year, month, day, sod / 3600, sod % 3600 / 60, sod % 60, d.subsec_nanos() / 1_000_000)
+}
+
+/// Days since 1970-01-01 to (year, month, day), proleptic Gregorian calendar
+pub fn civil_from_days(days: i64) -> (i64, u32, u32)
+{
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + (if month <= 2 { 1 } else { 0 });
+    (year, month, day)
+}
+
+/// (year, month, day) to days since 1970-01-01, inverse of civil_from_days
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64
+{
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub fn insert_u32(val: u32, pos: usize, to: &mut Vec<u8>) -> (u32, usize)
+{
+    let mut len = 0;
+    let mut sum = 0u32;
+    let mut val = val;
+    loop {
+        let v = (val % 10) as u8 + '0' as u8;
+        to.insert(pos, v);
+        sum += v as u32;
+        len += 1;
+        val = val / 10;
+        if val == 0 {
+            break;
+        }
+    }
+    (sum, len)
+}
+
+/// Repeating group that is currently being written
+struct GroupState
+{
+    no_tag: u32,
+    delimiter: Option<u32>,
+    declared: Option<u32>,
+    entries: u32,
+    // position of the SOH terminating NoXXX field, count is inserted in front of it
+    count_pos: usize,
+}
+
 pub struct FixMessageWriter<T>
 {
     version: Option<String>,
     sum: u32,
     len: usize,
     buf: Vec<u8>,
+    groups: Vec<GroupState>,
+    dictionary: Option<Rc<FixDictionary>>,
+    // where current message starts and where its BodyLength goes
+    start: usize,
+    len_pos: usize,
     _phantom: PhantomData<T>,
 }
 
@@ -206,18 +295,139 @@ impl<T> FixMessageWriter<T>
             sum: 0,
             len: 0,
             buf: vec![0u8;0],
+            groups: vec![],
+            dictionary: None,
+            start: 0,
+            len_pos: 0,
             _phantom: PhantomData,
         }
     }
 
+    /// Dictionary used to check that every group entry starts with the delimiter field
+    pub fn set_dictionary(&mut self, dictionary: Rc<FixDictionary>)
+    {
+        self.dictionary = Some(dictionary);
+    }
+
+    /// Opens repeating group counted by `no_tag`.
+    /// If `count` is provided it's written right away and checked once group is done,
+    /// otherwise the number of entries is filled in when group is done.
+    pub fn group_start(&mut self, no_tag: u32, count: Option<u32>)
+    {
+        let delimiter = self.dictionary.as_ref().and_then(|d| d.group_delimiter(no_tag));
+        let (sum, len) = put_tag_id_eq(no_tag, &mut self.buf);
+        self.sum += sum;
+        self.len += len;
+        if let Some(count) = count {
+            let pos = self.buf.len();
+            let (sum, len) = insert_u32(count, pos, &mut self.buf);
+            self.sum += sum;
+            self.len += len;
+        }
+        let count_pos = self.buf.len();
+        self.buf.push(SOH);
+        self.sum += SOH as u32;
+        self.len += 1;
+        self.groups.push(GroupState {
+            no_tag: no_tag,
+            delimiter: delimiter,
+            declared: count,
+            entries: 0,
+            count_pos: count_pos,
+        });
+    }
+
+    /// Starts new entry of the innermost open group, `tag` is the first field of the entry
+    pub fn group_entry(&mut self, tag: u32, value: &[u8]) -> Result<(), FixStreamException>
+    {
+        {
+            let group = match self.groups.last_mut() {
+                Some(group) => group,
+                None => return Err(format!("Group entry {} outside of group", tag)),
+            };
+            match group.delimiter {
+                Some(delimiter) if delimiter != tag => {
+                    return Err(format!("Group {} entry has to start with {} got {}",
+                                       group.no_tag, delimiter, tag));
+                },
+                _ => {},
+            }
+            group.entries += 1;
+        }
+        self.tag_value(tag, value);
+        Ok(())
+    }
+
+    /// Closes innermost open group, filling in or checking the number of entries
+    pub fn group_done(&mut self) -> Result<(), FixStreamException>
+    {
+        let group = match self.groups.pop() {
+            Some(group) => group,
+            None => return Err(String::from("No open group")),
+        };
+        match group.declared {
+            Some(declared) if declared != group.entries => {
+                Err(format!("Group {} declared {} entries got {}", group.no_tag, declared, group.entries))
+            },
+            Some(_) => Ok(()),
+            None => {
+                let (sum, len) = insert_u32(group.entries, group.count_pos, &mut self.buf);
+                self.sum += sum;
+                self.len += len;
+                // inner groups are always closed first so nothing to shift
+                Ok(())
+            },
+        }
+    }
+
     pub fn get_bytes(&self) -> &[u8]
     {
         &self.buf[..]
     }
 
+    /// Last message written (complete once fix_message_done is called)
+    pub fn last_message(&self) -> &[u8]
+    {
+        &self.buf[self.start..]
+    }
+
+    /// Drops the last message written, i.e. one that is stored rather than sent
+    pub fn discard_last(&mut self)
+    {
+        let start = self.start;
+        self.buf.truncate(start);
+    }
+
+    /// Closes groups left open and completes the message. If a group doesn't
+    /// add up or `res` is an error the message is dropped and the error returned.
+    pub fn message_done(&mut self, res: Result<(), FixStreamException>) -> Result<(), FixStreamException>
+    {
+        let mut res = res;
+        while !self.groups.is_empty() {
+            if let Err(e) = self.group_done() {
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        }
+        if res.is_err() {
+            // message is not sent, drop what was written
+            let start = self.start;
+            self.buf.truncate(start);
+            return res;
+        }
+        let (sum, _) = insert_u32(self.len as u32, self.len_pos, &mut self.buf);
+        self.sum += sum;
+        put_tag_id_eq(trailer::CheckSum, &mut self.buf);
+        let chksum = format!("{:03}", self.sum % 256);
+        put_tag_val_soh(chksum.as_bytes(), &mut self.buf);
+        Ok(())
+    }
+
     pub fn drain_head(&mut self, len: usize)
     {
         let _ : Vec<u8> = self.buf.drain(0..len).collect();
+        self.start = self.start.saturating_sub(len);
     }
 }
 
@@ -229,18 +439,21 @@ where T: FixAppMsgType
     fn fix_message_start(&mut self, msg_type: FixMsgType<Self::MSG_TYPES>, is_replayable: bool)
     {
         self.sum = 0;
-        self.len = 0;
+        self.start = self.buf.len();
         let version = self.version.take().unwrap();
         self.tag_value(header::Version, version.as_bytes()); 
-        self.tag_value(header::Length, "000".as_bytes());
+        // BodyLength is inserted here once the message is done
+        self.tag_value(header::Length, b"");
+        self.len_pos = self.buf.len() - 1;
+        self.len = 0;
         self.tag_value(header::MsgType, msg_type.as_bytes()); 
         self.version = Some(version);
     }
 
+    /// Use message_done to learn why the message was dropped
     fn fix_message_done(&mut self, res: Result<(), FixStreamException>)
     {
-        put_tag_id_eq(trailer::CheckSum, &mut self.buf);
-        put_u32_soh((self.sum % 256), &mut self.buf);
+        let _ = self.message_done(res);
     }
 }
 